@@ -6,8 +6,8 @@ use bevy::prelude::*;
 //     };
 // // use bevy::log::once;
 
-use utg::fly_camera::{FlyCamPlugin, FlyCam};
-use utg::world_generation::{WorldGenerationPlugin, WorldFocusPoint};
+use utg::fly_camera::FlyCamPlugin;
+use utg::world_generation::WorldGenerationPlugin;
 
 // #[derive(Component)]
 // struct CustomUV;
@@ -23,7 +23,11 @@ fn main() {
             ..default()
         }))
         .add_plugins(FlyCamPlugin)
-        .add_plugins(WorldGenerationPlugin)
+        .add_plugins(WorldGenerationPlugin {
+            rule_file: arg_value("--rules"),
+            reachability: true,
+            ..default()
+        })
         .insert_resource(ClearColor(Color::hex("61adb0").unwrap()))
         .add_systems(Startup, setup)
         // .add_systems(Update, tie_focus_to_cam)
@@ -31,6 +35,11 @@ fn main() {
         .run();
 }
 
+// the value after the flag on the command line
+fn arg_value(flag: &str) -> Option<String> {
+    std::env::args().skip_while(|arg| arg != flag).nth(1)
+}
+
 fn setup(mut commands: Commands) {
     let transform = Transform::from_xyz(0.0, 10.0, 0.0).looking_at(Vec3::ZERO, Vec3::Y);
 
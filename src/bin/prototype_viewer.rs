@@ -7,7 +7,9 @@ use bevy::utils::HashMap;
 use rand::random;
 
 use utg::fly_camera::FlyCamPlugin;
-use utg::world_generation::{prototype::*, TILE_SIZE};
+use utg::world_generation::{dims::ChunkDims, prototype::*};
+
+const TILE_SIZE: f32 = ChunkDims::DEFAULT.tile_size();
 
 fn main() {
     use PrototypesLoadState as PLS;
@@ -134,7 +136,7 @@ fn display_colored_sockets(mut gizmos: Gizmos, prts: Res<Prototypes>, colors: Re
         gizmos.rect(
             pos + Vec3::Y * 0.5,
             Quat::from_rotation_x(PI / 2.0),
-            Vec2::splat(TILE_SIZE as f32 * 0.9),
+            Vec2::splat(TILE_SIZE * 0.9),
             colors.0[&prt.p_y],
         );
 
@@ -142,7 +144,7 @@ fn display_colored_sockets(mut gizmos: Gizmos, prts: Res<Prototypes>, colors: Re
         gizmos.rect(
             pos - Vec3::Y * 0.5,
             Quat::from_rotation_x(PI / 2.0),
-            Vec2::splat(TILE_SIZE as f32 * 0.9),
+            Vec2::splat(TILE_SIZE * 0.9),
             colors.0[&prt.n_y],
         );
 
@@ -150,7 +152,7 @@ fn display_colored_sockets(mut gizmos: Gizmos, prts: Res<Prototypes>, colors: Re
         gizmos.rect(
             pos + Vec3::X * 0.5,
             Quat::from_rotation_y(PI / 2.0),
-            Vec2::splat(TILE_SIZE as f32 * 0.9),
+            Vec2::splat(TILE_SIZE * 0.9),
             colors.0[&prt.p_x],
         );
 
@@ -158,7 +160,7 @@ fn display_colored_sockets(mut gizmos: Gizmos, prts: Res<Prototypes>, colors: Re
         gizmos.rect(
             pos - Vec3::X * 0.5,
             Quat::from_rotation_y(PI / 2.0),
-            Vec2::splat(TILE_SIZE as f32 * 0.9),
+            Vec2::splat(TILE_SIZE * 0.9),
             colors.0[&prt.n_x],
         );
 
@@ -166,7 +168,7 @@ fn display_colored_sockets(mut gizmos: Gizmos, prts: Res<Prototypes>, colors: Re
         gizmos.rect(
             pos + Vec3::Z * 0.5,
             Quat::from_rotation_x(0.0),
-            Vec2::splat(TILE_SIZE as f32 * 0.9),
+            Vec2::splat(TILE_SIZE * 0.9),
             colors.0[&prt.p_z],
         );
 
@@ -174,7 +176,7 @@ fn display_colored_sockets(mut gizmos: Gizmos, prts: Res<Prototypes>, colors: Re
         gizmos.rect(
             pos - Vec3::Z * 0.5,
             Quat::from_rotation_x(0.0),
-            Vec2::splat(TILE_SIZE as f32 * 0.9),
+            Vec2::splat(TILE_SIZE * 0.9),
             colors.0[&prt.n_z],
         );
     }
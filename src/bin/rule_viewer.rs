@@ -10,13 +10,17 @@ use utg::fly_camera::FlyCamPlugin;
 use utg::world_generation::dir::Dir;
 use utg::world_generation::prototype::*;
 use utg::world_generation::tile::*;
-use utg::world_generation::TILE_SIZE;
+use utg::world_generation::dims::ChunkDims;
+use utg::world_generation::rule_file::export_rule_set;
+use utg::world_generation::validation::report_rule_issues;
 
+const TILE_SIZE: f32 = ChunkDims::DEFAULT.tile_size();
 const DISPLAY_AREA_SIZE: f32 = 4. * TILE_SIZE;
 
 fn main() {
     use PrototypesLoadState as PLS;
-    App::new()
+    let mut app = App::new();
+    app
         .add_plugins(
             DefaultPlugins
                 .set(WindowPlugin {
@@ -44,13 +48,24 @@ fn main() {
             check_prototypes_loaded.run_if(in_state(PLS::Loading)),
         )
         .add_systems(OnEnter(PLS::Finished), generate_tiles_and_rules)
+        .add_systems(
+            OnEnter(PLS::Finished),
+            report_rule_issues.after(generate_tiles_and_rules),
+        )
         .add_systems(
             OnEnter(PLS::Finished),
             spawn_rule_examples.after(generate_tiles_and_rules),
         )
         .add_systems(Update, tie_light_to_cam)
-        .add_systems(Update, grid_gizmo)
-        .run();
+        .add_systems(Update, grid_gizmo);
+    // writes the generated rules to a .ron or .json file, to review them or load them in the game
+    if let Some(path) = std::env::args().skip_while(|arg| arg != "--export").nth(1) {
+        app.add_systems(
+            OnEnter(PLS::Finished),
+            export_rule_set(path).after(generate_tiles_and_rules),
+        );
+    }
+    app.run();
 }
 
 #[derive(Component)]
@@ -79,7 +94,7 @@ fn tie_light_to_cam(
 ) {
     let trfm = query.single();
     let mut light_trfm = light.single_mut();
-    light_trfm.translation = trfm.translation.clone();
+    light_trfm.translation = trfm.translation;
 }
 
 // FIXME: This should spawn a grid, but it spawns them in a line
@@ -145,7 +160,7 @@ fn grid_gizmo(mut gizmos: Gizmos) {
             gizmos.rect(
                 Vec3::new(x as f32, 0.0, z as f32),
                 Quat::from_rotation_x(PI / 2.0),
-                Vec2::splat(TILE_SIZE as f32),
+                Vec2::splat(TILE_SIZE),
                 Color::YELLOW,
             )
         }
@@ -1,14 +1,17 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
 use std::fmt::Display;
-use std::ops::RangeBounds;
-use std::usize;
+use std::sync::Arc;
+use std::time::Duration;
 
 use super::dir::Dir;
-use super::{AdjRuleSet, AdjacencyRules, Tile, TileID, Tiles, CHUNK_HIGHT};
-use super::{CHUNK_SIZE, CHUNK_VOLUME};
+use super::{AdjRuleSet, AdjacencyRules, ChunkDims, Tile, TileID, TileMask, Tiles, MAX_TILES};
+use super::{ChunkScheduler, HeightField, WeightField, WorldConstraints, WorldMap, WorldSeed};
+use bevy::log::{debug_span, info_span, trace_span};
 use bevy::prelude::*;
-use bevy::utils::HashMap;
-use rand::rngs::ThreadRng;
-use rand::Rng;
+use bevy::utils::{HashMap, HashSet, Instant};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 use strum::IntoEnumIterator;
 
 use super::util::*;
@@ -29,9 +32,9 @@ impl ChunkId {
         self.0.y
     }
 
-    pub fn from_position(pos: Vec3) -> Self {
-        let x = (pos.x / CHUNK_SIZE as f32).floor() as i32;
-        let z = (pos.z / CHUNK_SIZE as f32).floor() as i32;
+    pub fn from_position(pos: Vec3, dims: &ChunkDims) -> Self {
+        let x = (pos.x / dims.extent()).floor() as i32;
+        let z = (pos.z / dims.extent()).floor() as i32;
         Self::new(x, z)
     }
 
@@ -44,355 +47,2910 @@ impl ChunkId {
         self.0.y += offset;
         self
     }
+
+    // world tile coordinates of the cell at the chunk's origin
+    pub fn origin(&self, dims: &ChunkDims) -> IVec2 {
+        self.0 * dims.size() as i32
+    }
 }
 
+#[derive(Clone, PartialEq)]
 pub struct Chunk {
     id: ChunkId,
+    dims: ChunkDims,
     tiles: Vec<Option<TileID>>,
 }
 
-impl Chunk {
-    pub fn new(id: ChunkId, ground: Option<TileID>) -> Self {
-        let mut tiles = vec![None; CHUNK_VOLUME];
-        for x in 0..CHUNK_SIZE {
-            for z in 0..CHUNK_SIZE {
-                tiles[get_index(x, 0, z)] = ground.clone();
+impl Chunk {
+    pub fn new(id: ChunkId, dims: ChunkDims, ground: Option<TileID>) -> Self {
+        let mut tiles = vec![None; dims.volume()];
+        for x in 0..dims.size() {
+            for z in 0..dims.size() {
+                tiles[dims.index(x, 0, z)] = ground;
+            }
+        }
+        Self { id, dims, tiles }
+    }
+
+    pub fn id(&self) -> ChunkId {
+        self.id.clone()
+    }
+
+    pub fn dims(&self) -> ChunkDims {
+        self.dims
+    }
+
+    pub fn get_tile(&self, x: usize, y: usize, z: usize) -> Option<TileID> {
+        self.tiles[self.dims.index(x, y, z)]
+    }
+
+    pub fn set_tile(&mut self, x: usize, y: usize, z: usize, tile: Option<TileID>) {
+        self.tiles[self.dims.index(x, y, z)] = tile;
+    }
+
+    // If every walkable cell of the chunk can be reached from every other one within the chunk,
+    // like with_reachability demands. Empty cells are not walkable.
+    pub fn is_reachable(&self, tiles: &Tiles) -> bool {
+        let mut builder = ChunkBuilder::new(self.id(), self.dims());
+        (builder.walkable, builder.ramps) =
+            walk_masks(tiles.0.iter().map(|(id, tile)| (*id, tile)));
+        let not_walkable = !builder.walkable;
+        builder.wave = self
+            .tiles
+            .iter()
+            .map(|tile| tile.map_or(not_walkable, TileMask::single))
+            .collect();
+        let mut area = vec![usize::MAX; self.dims().volume()];
+        builder.label_walk_areas(&mut area, &mut vec![]).is_ok()
+    }
+
+    pub fn pos(&self) -> Vec3 {
+        let x = self.id.x() as f32;
+        let z = self.id.z() as f32;
+        Vec3::new(x, 0.0, z) * self.dims.extent()
+    }
+}
+
+// Everything the chunks of a world are generated from, apart from the world map itself.
+#[derive(Clone, Copy)]
+pub struct ChunkContext<'a> {
+    pub tiles: &'a Tiles,
+    pub rule_set: &'a AdjRuleSet,
+    pub height_field: Option<&'a HeightField>,
+    pub weight_field: Option<&'a Arc<dyn WeightField>>,
+    pub seed: WorldSeed,
+    pub constraints: Option<&'a WorldConstraints>,
+}
+
+impl<'a> ChunkContext<'a> {
+    pub fn new(tiles: &'a Tiles, rule_set: &'a AdjRuleSet) -> Self {
+        Self {
+            tiles,
+            rule_set,
+            height_field: None,
+            weight_field: None,
+            seed: WorldSeed::default(),
+            constraints: None,
+        }
+    }
+
+    pub fn with_seed(mut self, seed: WorldSeed) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn with_height_field(mut self, height_field: Option<&'a HeightField>) -> Self {
+        self.height_field = height_field;
+        self
+    }
+
+    pub fn with_weight_field(mut self, weight_field: Option<&'a Arc<dyn WeightField>>) -> Self {
+        self.weight_field = weight_field;
+        self
+    }
+
+    pub fn with_constraints(mut self, constraints: &'a WorldConstraints) -> Self {
+        self.constraints = Some(constraints);
+        self
+    }
+
+    fn chunk_constraints(&self, id: &ChunkId) -> Option<&'a ChunkConstraints> {
+        self.constraints?.get(id)
+    }
+
+    // the builder with everything but the neighbors and the constraints
+    fn builder(&self, builder: ChunkBuilder, seed: u64) -> ChunkBuilder {
+        let mut builder = builder.with_seed(seed).add_rule_set(self.rule_set.clone());
+        if let Some(height_field) = self.height_field {
+            builder = builder.with_height_field(height_field.clone());
+        }
+        if let Some(weight_field) = self.weight_field {
+            builder = builder.with_weight_field(weight_field.clone());
+        }
+        builder
+    }
+}
+
+// Generates the chunk and adds it to the world map, see generate_chunks. When the chunk can not
+// be solved next to the chunks around it, it is repaired, which also modifies the neighboring
+// chunks. Returns every chunk that was changed.
+pub fn generate_chunk(
+    world_map: &mut WorldMap,
+    id: &ChunkId,
+    ctx: ChunkContext,
+) -> Result<Vec<ChunkId>, WfcError> {
+    generate_chunks(world_map, std::slice::from_ref(id), ctx)
+}
+
+// Generates the chunks with a scheduler, the same way the game does, and adds them to the world
+// map. Chunks that are already loaded are skipped. Returns every chunk that was changed.
+pub fn generate_chunks(
+    world_map: &mut WorldMap,
+    ids: &[ChunkId],
+    ctx: ChunkContext,
+) -> Result<Vec<ChunkId>, WfcError> {
+    let mut scheduler = ChunkScheduler::default();
+    for id in ids.iter().filter(|id| !world_map.contains(id)) {
+        scheduler.queue(id.clone());
+    }
+    let mut changed = vec![];
+    while !scheduler.is_idle() {
+        scheduler.start(world_map, ctx);
+        let update = scheduler.finish(world_map, ctx, true);
+        if let Some((_, e)) = update.failed.into_iter().next() {
+            return Err(e);
+        }
+        for id in update.changed {
+            if !changed.contains(&id) {
+                changed.push(id);
+            }
+        }
+    }
+    Ok(changed)
+}
+
+// Weights are fixed point numbers with 16 fractional bits, capped so the sums of 256 tiles fit.
+const WEIGHT_SCALE: f64 = 65536.0;
+const MAX_FIXED_WEIGHT: f64 = u32::MAX as f64;
+
+// the supports of a tile count the tiles of a neighbor
+const _: () = assert!(MAX_TILES <= u16::MAX as usize);
+
+// a positive weight as fixed point number, weights too small to be represented get the smallest
+// one, so the tile stays possible
+fn fixed_weight(weight: f64) -> u64 {
+    (weight * WEIGHT_SCALE).round().clamp(1.0, MAX_FIXED_WEIGHT) as u64
+}
+
+// weight * log2(weight) of a fixed point weight, rounded so adding and subtracting it is exact
+fn weight_log(weight: u64) -> i64 {
+    let weight = weight as f64;
+    (weight * weight.log2()).round() as i64
+}
+
+// The chunk grid is colored like a checkerboard, chunks only share a border with chunks of the
+// other color.
+pub const CHUNK_COLORS: usize = 2;
+
+pub fn chunk_color(id: &ChunkId) -> usize {
+    (id.x() + id.z()).rem_euclid(CHUNK_COLORS as i32) as usize
+}
+
+// The builder generate_chunk uses before it falls back to a repair. It does not borrow the world
+// map, so it can be built somewhere else. Fails when the constraints of the chunk pin a cell
+// outside of it.
+pub fn chunk_builder(
+    world_map: &WorldMap,
+    id: &ChunkId,
+    ctx: ChunkContext,
+) -> Result<ChunkBuilder, WfcError> {
+    let builder = ChunkBuilder::new(id.clone(), world_map.dims()).with_neighbors(world_map);
+    let builder = ctx.builder(builder, ctx.seed.chunk_seed(id));
+    match ctx.chunk_constraints(id) {
+        Some(constraints) => constraints.apply(id, builder, world_map, ctx.tiles),
+        None => Ok(builder),
+    }
+}
+
+// If the chunk joins legally with the chunks that are loaded around it. A chunk that was built
+// somewhere else may not, when its neighbors changed in the meantime.
+pub fn fits_neighbors(chunk: &Chunk, world_map: &WorldMap, rule_set: &AdjRuleSet) -> bool {
+    let border = ChunkBuilder::new(chunk.id(), chunk.dims())
+        .with_neighbors(world_map)
+        .border;
+    border.into_iter().all(|(pos, dir, outside)| {
+        let Some(tile) = chunk.tiles[pos] else {
+            return true;
+        };
+        let allows = |tile: TileID, dir: Dir, other: TileID| {
+            rule_set
+                .0
+                .get(&tile)
+                .is_some_and(|rule| rule.mask(dir).contains(other))
+        };
+        allows(tile, dir, outside) && allows(outside, dir.opposite(), tile)
+    })
+}
+
+// Regenerates the blocks centered at the corners of the chunk, the chunk is added to the world
+// map if it is missing. Every block keeps the constraints of the chunks it overlaps. A changed
+// chunk that misses its tile counts or is no longer connected as a whole is generated again, the
+// repair fails with TileCount or Unreachable when that is not possible. On an error the world map is left as it was, apart from the added chunk.
+pub fn repair_chunk(
+    world_map: &mut WorldMap,
+    id: &ChunkId,
+    ctx: ChunkContext,
+) -> Result<Vec<ChunkId>, WfcError> {
+    if !world_map.contains(id) {
+        world_map.add_chunk(Chunk::new(id.clone(), world_map.dims(), None));
+    }
+    let backup: Vec<Chunk> = repair_area(id)
+        .filter_map(|id| world_map.get(&id).cloned())
+        .collect();
+
+    let dims = world_map.dims();
+    let size = dims.size() as i32;
+    let seed = ctx.seed.chunk_seed(id);
+    let mut changed = vec![id.clone()];
+    for (i, corner) in [(0, 0), (1, 0), (0, 1), (1, 1)].into_iter().enumerate() {
+        let origin = id.origin(&dims) + IVec2::new(corner.0, corner.1) * size - size / 2;
+        let block = ChunkBuilder::block(origin, dims).with_neighbors(world_map);
+        let mut builder = Ok(ctx.builder(block, mix_seed(seed, i as u64 + 1)));
+        let first = origin.div_euclid(IVec2::splat(size));
+        let last = (origin + size - 1).div_euclid(IVec2::splat(size));
+        for x in first.x..=last.x {
+            for z in first.y..=last.y {
+                // the cells of chunks that are not loaded are dropped
+                let chunk = ChunkId::new(x, z);
+                let constraints = ctx.chunk_constraints(&chunk);
+                if let Some(constraints) = constraints.filter(|_| world_map.contains(&chunk)) {
+                    builder = builder.and_then(|builder| {
+                        constraints.apply(&chunk, builder, world_map, ctx.tiles)
+                    });
+                }
+            }
+        }
+        let result = builder.and_then(|builder| builder.build_into(ctx.tiles, world_map));
+        match result {
+            Ok(ids) => {
+                for id in ids {
+                    if !changed.contains(&id) {
+                        changed.push(id);
+                    }
+                }
+            }
+            Err(e) => {
+                for chunk in backup {
+                    world_map.add_chunk(chunk);
+                }
+                return Err(e);
+            }
+        }
+    }
+    // the blocks only count and connect the cells within themselves, a chunk they left without
+    // its counts or apart is generated again as a whole
+    for id in changed.iter() {
+        let (Some(constraints), Some(chunk)) = (ctx.chunk_constraints(id), world_map.get(id))
+        else {
+            continue;
+        };
+        let Some(violation) = constraints.violation(chunk, ctx.tiles) else {
+            continue;
+        };
+        let rebuilt =
+            chunk_builder(world_map, id, ctx).and_then(|builder| builder.build(ctx.tiles));
+        match rebuilt {
+            Ok(chunk) => world_map.add_chunk(chunk),
+            Err(_) => {
+                for chunk in backup {
+                    world_map.add_chunk(chunk);
+                }
+                return Err(violation);
+            }
+        }
+    }
+    Ok(changed)
+}
+
+// A repair that ran on a copy of the chunks around the repaired one, see repair_task.
+pub struct ChunkRepair {
+    id: ChunkId,
+    // the chunks around the repaired one before and after the repair
+    base: WorldMap,
+    repaired: WorldMap,
+    changed: Vec<ChunkId>,
+}
+
+impl ChunkRepair {
+    pub fn id(&self) -> ChunkId {
+        self.id.clone()
+    }
+
+    // Writes the changed chunks into the world map and returns them. Nothing is written when the
+    // chunks around the repaired one changed since the repair started, then it has to run again.
+    pub fn apply(self, world_map: &mut WorldMap) -> Option<Vec<ChunkId>> {
+        let unchanged = repair_area(&self.id).all(|id| world_map.get(&id) == self.base.get(&id));
+        if !unchanged {
+            return None;
+        }
+        for id in self.changed.iter() {
+            let chunk = self
+                .repaired
+                .get(id)
+                .expect("changed chunks are in the copy");
+            world_map.add_chunk(chunk.clone());
+        }
+        Some(self.changed)
+    }
+}
+
+// The repair of the chunk as a future that does not borrow anything, so it can run on a task
+// pool. It repairs a copy of the chunks around the chunk, apply the result to the world map.
+pub fn repair_task(
+    world_map: &WorldMap,
+    id: &ChunkId,
+    ctx: ChunkContext,
+) -> impl std::future::Future<Output = Result<ChunkRepair, WfcError>> + Send + 'static {
+    let mut base = WorldMap::new(world_map.dims());
+    let mut constraints = WorldConstraints::default();
+    for id in repair_area(id) {
+        if let Some(chunk) = world_map.get(&id) {
+            base.add_chunk(chunk.clone());
+        }
+        if let Some(chunk_constraints) = ctx.chunk_constraints(&id) {
+            constraints.chunks.insert(id, chunk_constraints.clone());
+        }
+    }
+    let id = id.clone();
+    let tiles = ctx.tiles.clone();
+    let rule_set = ctx.rule_set.clone();
+    let height_field = ctx.height_field.cloned();
+    let weight_field = ctx.weight_field.cloned();
+    let seed = ctx.seed;
+    async move {
+        let ctx = ChunkContext::new(&tiles, &rule_set)
+            .with_seed(seed)
+            .with_height_field(height_field.as_ref())
+            .with_weight_field(weight_field.as_ref())
+            .with_constraints(&constraints);
+        let mut repaired = base.clone();
+        let changed = repair_chunk(&mut repaired, &id, ctx)?;
+        Ok(ChunkRepair {
+            id,
+            base,
+            repaired,
+            changed,
+        })
+    }
+}
+
+// the walkable tiles and the ramps leading up in each direction, indexed by Dir
+fn walk_masks<'a>(tiles: impl Iterator<Item = (TileID, &'a Tile)>) -> (TileMask, [TileMask; 6]) {
+    let mut walkable = TileMask::EMPTY;
+    let mut ramps = [TileMask::EMPTY; 6];
+    for (id, tile) in tiles {
+        // a ramp can always be walked on
+        if tile.walkable || tile.ramp.is_some() {
+            walkable.insert(id);
+        }
+        if let Some(dir) = tile.ramp {
+            ramps[dir as usize].insert(id);
+        }
+    }
+    (walkable, ramps)
+}
+
+// the chunk and the chunks around it, the blocks of a repair only reach into them
+fn repair_area(id: &ChunkId) -> impl Iterator<Item = ChunkId> + '_ {
+    (-1..=1).flat_map(move |x| (-1..=1).map(move |z| id.clone().x_offset(x).z_offset(z)))
+}
+
+// Chunk Generatorion
+
+pub struct ChunkBuilder {
+    id: ChunkId,
+    dims: ChunkDims,
+    // world tile coordinates of the first cell, chunks start at their origin but blocks can start
+    // anywhere
+    origin: IVec2,
+    // the remaining tiles of every cell, a cell is collapsed once a single tile is left
+    wave: Vec<TileMask>,
+    rules: HashMap<TileID, AdjacencyRules>,
+    // tiles of already generated chunks right outside the border cells, as the border cell,
+    // the direction from it to the outside cell and the tile of the outside cell
+    border: Vec<(usize, Dir, TileID)>,
+    // cells that are restricted to the given tiles before anything is collapsed
+    pins: Vec<(UVec3, TileMask)>,
+    counts: Vec<TileCount>,
+    // the state of every tile count, in the same order as counts
+    count_states: Vec<CountState>,
+    // seen cells, stack and cells outside of the clusters of the last cluster count, kept to
+    // reuse their memory
+    cluster_seen: Vec<bool>,
+    cluster_stack: Vec<usize>,
+    cluster_open: Vec<usize>,
+    // restricts the walkable tiles to the levels around the surface
+    height_field: Option<HeightField>,
+    // every walkable cell has to be reachable from every other one
+    reachability: bool,
+    walkable: TileMask,
+    // ramps leading up in each direction, indexed by Dir
+    ramps: [TileMask; 6],
+    // a cell lost its last walkable, non walkable or ramp tile since the last reachability check
+    walkable_changed: bool,
+    // labels and stack of the last reachability check, kept to reuse their memory
+    walk_areas: Vec<usize>,
+    walk_stack: Vec<usize>,
+    // number of tile slots, every TileID of the rule set is smaller than this
+    tile_count: usize,
+    // for every tile and direction the tiles that may be placed next to it, both tiles have to
+    // allow the connection
+    compatible: Vec<[TileMask; 6]>,
+    // for every cell, tile and direction the number of tiles in the neighboring cell that are
+    // compatible with the tile, the tile is removed as soon as one of them drops to zero. A count
+    // is at most MAX_TILES, so u16 is enough. Together with the weights every cell takes 20 bytes
+    // per tile slot, about 21 MB for 256 tiles in a 32x4x32 chunk.
+    supports: Vec<[u16; 6]>,
+    // removals that still have to be propagated
+    pending: Vec<(usize, TileID)>,
+    // every removal since the oldest decision that can still be undone
+    trail: Vec<(usize, TileID)>,
+    decisions: Vec<Decision>,
+    selector: CellSelector,
+    // weight of every cell and tile slot in fixed point, zero for slots that are not in the rule
+    // set. Integer weights keep the sums exact however often tiles are removed and restored.
+    weights: Vec<u64>,
+    // sum of the weights and of weight * log2(weight) of the remaining tiles of every cell
+    weight_sums: Vec<u64>,
+    weight_log_sums: Vec<i64>,
+    // replaces the weights of the tiles in every cell
+    weight_field: Option<Arc<dyn WeightField>>,
+    // open cells ordered by the selector, outdated entries are skipped when they come up
+    queue: BinaryHeap<Candidate>,
+    // bumped whenever a cell changes, an entry is outdated if its version does not match
+    versions: Vec<u32>,
+    // cells that changed since the queue was last updated
+    touched: Vec<usize>,
+    limits: SolverLimits,
+    backtracks: usize,
+    restarts: usize,
+    stats: WfcStats,
+    initialized: bool,
+    // cell that is collapsed next, because its last tile was ruled out by a backtrack
+    retry: Option<usize>,
+    rng: ChaCha8Rng,
+}
+
+// An entry of the cell queue, the candidate with the lowest priority is popped first
+struct Candidate {
+    priority: f32,
+    pos: usize,
+    version: u32,
+}
+
+// A collapse the solver can undo, trail_len is the length of the trail before it was made
+struct Decision {
+    pos: usize,
+    tile: TileID,
+    trail_len: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SolverLimits {
+    // number of collapses that can be undone, older ones are kept for good. All of them by default
+    pub max_depth: usize,
+    // number of backtracks before the solver gives up or restarts
+    pub max_backtracks: usize,
+    // number of times the solver starts over from the initial wave
+    pub max_restarts: usize,
+}
+
+// Limits how many cells of a region may hold tiles of a prototype, in any rotation. The limits
+// are enforced while solving, so a chunk that can not meet them fails to build. They count cells,
+// a 2x2 patch of the prototype counts as 4, or with clusters the groups of cells of the prototype
+// that touch each other, the patch counts as 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TileCount {
+    pub prototype: String,
+    // the first cell and the cell past the last one of the region, the whole chunk by default
+    pub region: (UVec3, UVec3),
+    pub min: usize,
+    pub max: usize,
+    // count the groups of cells connected through their faces within the region, not the cells
+    pub clusters: bool,
+}
+
+impl TileCount {
+    pub fn new(prototype: &str, dims: &ChunkDims) -> Self {
+        let end = UVec3::new(dims.size() as u32, dims.height() as u32, dims.size() as u32);
+        Self {
+            prototype: prototype.to_string(),
+            region: (UVec3::ZERO, end),
+            min: 0,
+            max: usize::MAX,
+            clusters: false,
+        }
+    }
+
+    pub fn clusters(mut self) -> Self {
+        self.clusters = true;
+        self
+    }
+
+    pub fn in_region(mut self, start: UVec3, end: UVec3) -> Self {
+        self.region = (start, end.min(self.region.1));
+        self
+    }
+
+    pub fn at_least(mut self, min: usize) -> Self {
+        self.min = min;
+        self
+    }
+
+    pub fn at_most(mut self, max: usize) -> Self {
+        self.max = max;
+        self
+    }
+
+    // share of the cells of the region, so set the region first
+    pub fn at_least_share(self, share: f32) -> Self {
+        let min = (share * self.volume() as f32).ceil() as usize;
+        self.at_least(min)
+    }
+
+    pub fn at_most_share(self, share: f32) -> Self {
+        let max = (share * self.volume() as f32).floor() as usize;
+        self.at_most(max)
+    }
+
+    fn volume(&self) -> usize {
+        let (start, end) = self.region;
+        (end.saturating_sub(start))
+            .to_array()
+            .iter()
+            .product::<u32>() as usize
+    }
+
+    fn contains(&self, cell: UVec3) -> bool {
+        let (start, end) = self.region;
+        cell.cmpge(start).all() && cell.cmplt(end).all()
+    }
+
+    fn matches(&self, tiles: &Tiles, tile: TileID) -> bool {
+        tiles
+            .0
+            .get(&tile)
+            .is_some_and(|tile| tile.prototype == self.prototype)
+    }
+
+    // the cells or clusters of the prototype in the region of a generated chunk
+    pub fn counted(&self, chunk: &Chunk, tiles: &Tiles) -> usize {
+        let dims = chunk.dims();
+        let cells: Vec<UVec3> = (0..dims.volume())
+            .filter(|&pos| chunk.tiles[pos].is_some_and(|tile| self.matches(tiles, tile)))
+            .map(|pos| {
+                let (x, y, z) = dims.from_index(pos);
+                UVec3::new(x as u32, y as u32, z as u32)
+            })
+            .filter(|&cell| self.contains(cell))
+            .collect();
+        match self.clusters {
+            true => clusters(&cells),
+            false => cells.len(),
+        }
+    }
+}
+
+// the number of groups the cells form, cells are grouped when they share a face
+fn clusters(cells: &[UVec3]) -> usize {
+    let mut left: HashSet<IVec3> = cells.iter().map(|cell| cell.as_ivec3()).collect();
+    let mut clusters = 0;
+    while let Some(&start) = left.iter().next() {
+        left.remove(&start);
+        let mut stack = vec![start];
+        while let Some(cell) = stack.pop() {
+            for dir in Dir::iter() {
+                let next = cell + dir.to_vec3().as_ivec3();
+                if left.remove(&next) {
+                    stack.push(next);
+                }
+            }
+        }
+        clusters += 1;
+    }
+    clusters
+}
+
+// Constraints of a single chunk on top of the rules. They are kept whenever the chunk is generated
+// again, also by the blocks of a repair that overlap the chunk.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChunkConstraints {
+    // cells of the chunk and the tiles they are restricted to
+    pub pins: Vec<(UVec3, TileMask)>,
+    // regions in the coordinates of the chunk
+    pub counts: Vec<TileCount>,
+    // the walkable cells are connected within the chunk, not across the chunk borders
+    pub reachability: bool,
+}
+
+impl ChunkConstraints {
+    pub fn pin(self, cell: UVec3, tile: TileID) -> Self {
+        self.pin_set(cell, TileMask::single(tile))
+    }
+
+    pub fn pin_set(mut self, cell: UVec3, tiles: TileMask) -> Self {
+        self.pins.push((cell, tiles));
+        self
+    }
+
+    pub fn with_count(mut self, count: TileCount) -> Self {
+        self.counts.push(count);
+        self
+    }
+
+    pub fn with_reachability(mut self) -> Self {
+        self.reachability = true;
+        self
+    }
+
+    // Adds the constraints of the chunk to a builder of the chunk or of a block that overlaps it.
+    // Pins outside of the builder are left out, counts are cut to the builder and lowered by the
+    // cells or clusters of their region outside of it. The reachability of a block is only checked within the
+    // block. Fails with PinOutside when a pin is not inside of the chunk.
+    fn apply(
+        &self,
+        id: &ChunkId,
+        mut builder: ChunkBuilder,
+        world_map: &WorldMap,
+        tiles: &Tiles,
+    ) -> Result<ChunkBuilder, WfcError> {
+        let size = builder.dims.size() as i32;
+        let chunk_origin = id.origin(&builder.dims);
+        let offset = chunk_origin - builder.origin;
+        let inside = |x: u32, z: u32| {
+            let (x, z) = (x as i32 + offset.x, z as i32 + offset.y);
+            ((0..size).contains(&x) && (0..size).contains(&z)).then_some((x as u32, z as u32))
+        };
+        for &(cell, mask) in self.pins.iter() {
+            if !builder
+                .dims
+                .contains(cell.x as usize, cell.y as usize, cell.z as usize)
+            {
+                return Err(WfcError::pin_outside(cell));
+            }
+            if let Some((x, z)) = inside(cell.x, cell.z) {
+                builder = builder.pin_set(UVec3::new(x, cell.y, z), mask);
+            }
+        }
+
+        for count in self.counts.iter() {
+            let (start, end) = count.region;
+            let cut = |value: u32, offset: i32| (value as i32 + offset).clamp(0, size) as u32;
+            let region = (
+                UVec3::new(cut(start.x, offset.x), start.y, cut(start.z, offset.y)),
+                UVec3::new(cut(end.x, offset.x), end.y, cut(end.z, offset.y)),
+            );
+            if region.0.x >= region.1.x || region.0.z >= region.1.z {
+                continue;
+            }
+            // the cells outside of the builder keep their tiles, empty ones are solved later
+            let (mut fixed, mut open) = (vec![], 0);
+            for x in start.x..end.x {
+                for z in start.z..end.z {
+                    if inside(x, z).is_some() {
+                        continue;
+                    }
+                    for y in start.y..end.y {
+                        let pos = chunk_origin + IVec2::new(x as i32, z as i32);
+                        match world_map.get_world_tile(IVec3::new(pos.x, y as i32, pos.y)) {
+                            Some(tile) if count.matches(tiles, tile) => {
+                                fixed.push(UVec3::new(x, y, z))
+                            }
+                            Some(_) => {}
+                            None => open += 1,
+                        }
+                    }
+                }
+            }
+            // clusters outside may join the ones inside, so the maximum is lowered by all of them
+            // and the minimum may be too low, repair_chunk checks the whole chunk afterwards
+            let fixed = match count.clusters {
+                true => clusters(&fixed),
+                false => fixed.len(),
+            };
+            builder = builder.with_count(TileCount {
+                prototype: count.prototype.clone(),
+                region,
+                min: count.min.saturating_sub(fixed + open),
+                max: count.max.saturating_sub(fixed),
+                clusters: count.clusters,
+            });
+        }
+
+        if self.reachability {
+            builder = builder.with_reachability();
+        }
+        Ok(builder)
+    }
+
+    // the first constraint the generated chunk does not meet, apart from the pins
+    fn violation(&self, chunk: &Chunk, tiles: &Tiles) -> Option<WfcError> {
+        for count in self.counts.iter() {
+            if !(count.min..=count.max).contains(&count.counted(chunk, tiles)) {
+                return Some(WfcError::TileCount(count.prototype.clone()));
+            }
+        }
+        if self.reachability && !chunk.is_reachable(tiles) {
+            return Some(WfcError::Unreachable(chunk.id()));
+        }
+        None
+    }
+}
+
+// Bounds of a tile count for the current wave, fixed cells can only hold tiles of the prototype,
+// possible cells can still hold one of them.
+struct CountState {
+    tiles: TileMask,
+    // a cell became or stopped being fixed or possible since the clusters were counted
+    changed: bool,
+    fixed: usize,
+    possible: usize,
+}
+
+// How the solver picks the next cell to collapse
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CellSelector {
+    // the cell with the fewest remaining tiles
+    #[default]
+    MinRemainingValues,
+    // the cell with the lowest weighted Shannon entropy, ties are broken randomly
+    Entropy,
+    // the first cell that is not collapsed, layer by layer
+    Scanline,
+    // the cell closest to the given cell, so the structure grows outwards from it
+    Distance(UVec3),
+}
+
+// What the solver did while building a chunk, to compare tilesets and settings. The counts and
+// times add up over restarts.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WfcStats {
+    pub collapses: usize,
+    // tiles ruled out by propagation, including the propagation of the initial wave
+    pub removals: usize,
+    pub backtracks: usize,
+    pub restarts: usize,
+    // propagations that left a cell without tiles
+    pub contradictions: usize,
+    // wall time of setting up the initial wave and propagating it
+    pub init_time: Duration,
+    // wall time of picking the cells to collapse
+    pub select_time: Duration,
+    // wall time of the collapses and their propagation
+    pub collapse_time: Duration,
+    // wall time of undoing collapses, including the propagation of the ruled out tiles
+    pub backtrack_time: Duration,
+}
+
+impl WfcStats {
+    pub fn total_time(&self) -> Duration {
+        self.init_time + self.select_time + self.collapse_time + self.backtrack_time
+    }
+}
+
+// What a single call of ChunkBuilder::step did to the wave
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WfcStep {
+    // the cell was collapsed into the tile, shrunk contains every cell that lost tiles
+    Collapsed {
+        cell: UVec3,
+        tile: TileID,
+        shrunk: Vec<UVec3>,
+    },
+    // a collapse lead to a contradiction and was undone, the tile is ruled out for the cell
+    Backtracked {
+        cell: UVec3,
+        tile: TileID,
+        changed: Vec<UVec3>,
+    },
+    // the solver ran out of backtracks and starts over from the initial wave
+    Restarted,
+    // every cell is collapsed
+    Finished,
+}
+
+impl Default for SolverLimits {
+    fn default() -> Self {
+        Self {
+            max_depth: usize::MAX,
+            max_backtracks: 10_000,
+            max_restarts: 3,
+        }
+    }
+}
+
+impl Default for ChunkBuilder {
+    fn default() -> Self {
+        Self {
+            id: ChunkId::default(),
+            dims: ChunkDims::default(),
+            origin: IVec2::ZERO,
+            wave: vec![],
+            rules: HashMap::default(),
+            border: vec![],
+            pins: vec![],
+            counts: vec![],
+            count_states: vec![],
+            cluster_seen: vec![],
+            cluster_stack: vec![],
+            cluster_open: vec![],
+            height_field: None,
+            reachability: false,
+            walkable: TileMask::EMPTY,
+            ramps: [TileMask::EMPTY; 6],
+            walkable_changed: false,
+            walk_areas: vec![],
+            walk_stack: vec![],
+            tile_count: 0,
+            compatible: vec![],
+            supports: vec![],
+            pending: vec![],
+            trail: vec![],
+            decisions: vec![],
+            selector: CellSelector::default(),
+            weights: vec![],
+            weight_sums: vec![],
+            weight_log_sums: vec![],
+            weight_field: None,
+            queue: BinaryHeap::new(),
+            versions: vec![],
+            touched: vec![],
+            limits: SolverLimits::default(),
+            backtracks: 0,
+            restarts: 0,
+            stats: WfcStats::default(),
+            initialized: false,
+            retry: None,
+            rng: ChaCha8Rng::seed_from_u64(0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WfcError {
+    // no tile is left for the cell at the given chunk coordinates
+    Contradiction { x: usize, y: usize, z: usize },
+    // the solver ran out of backtracks and restarts
+    BacktrackLimit { backtracks: usize, restarts: usize },
+    EmptyRuleSet,
+    // a tile of the rule set has no entry in Tiles or a weight of zero
+    MissingWeight(TileID),
+    // the example uses a tile that is not part of the tileset
+    UnknownTile(TileID),
+    // the tile count of the prototype can not be met
+    TileCount(String),
+    // the tileset needs more ids than a tile mask can hold, the count is the highest id plus one
+    TooManyTiles(usize),
+    // the example of an overlapping model has more patterns than a tile mask can hold
+    TooManyPatterns(usize),
+    // patterns of this size do not fit into the example or the chunk, the chunk is higher than
+    // the example, or the size is zero
+    PatternSize(usize),
+    // the chunks around the chunk changed every time it was generated
+    NeighborsChanged,
+    // a pin restricts a cell outside of the chunk
+    PinOutside { x: usize, y: usize, z: usize },
+    // the walkable cells of the repaired chunk are not connected anymore
+    Unreachable(ChunkId),
+}
+
+impl WfcError {
+    fn contradiction(cell: UVec3) -> Self {
+        let (x, y, z) = (cell.x as usize, cell.y as usize, cell.z as usize);
+        Self::Contradiction { x, y, z }
+    }
+
+    fn pin_outside(cell: UVec3) -> Self {
+        let (x, y, z) = (cell.x as usize, cell.y as usize, cell.z as usize);
+        Self::PinOutside { x, y, z }
+    }
+}
+
+impl Display for WfcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Contradiction { x, y, z } => {
+                write!(f, "WFC Error: contradiction at ({}, {}, {})", x, y, z)
+            }
+            Self::BacktrackLimit {
+                backtracks,
+                restarts,
+            } => write!(
+                f,
+                "WFC Error: no solution after {} backtracks and {} restarts",
+                backtracks, restarts
+            ),
+            Self::EmptyRuleSet => write!(f, "WFC Error: the rule set is empty"),
+            Self::MissingWeight(id) => write!(f, "WFC Error: {:?} has no weight", id),
+            Self::UnknownTile(id) => write!(f, "WFC Error: {:?} is not in the tileset", id),
+            Self::TileCount(prototype) => {
+                write!(
+                    f,
+                    "WFC Error: the tile count of {} can not be met",
+                    prototype
+                )
+            }
+            Self::TooManyTiles(count) => {
+                write!(
+                    f,
+                    "WFC Error: {} tiles exceed the limit of {} tiles",
+                    count, MAX_TILES
+                )
+            }
+            Self::TooManyPatterns(count) => {
+                write!(f, "WFC Error: {} patterns exceed the limit of tiles", count)
+            }
+            Self::PatternSize(n) => {
+                write!(f, "WFC Error: patterns of size {} do not fit", n)
+            }
+            Self::PinOutside { x, y, z } => {
+                write!(
+                    f,
+                    "WFC Error: the pinned cell ({}, {}, {}) is outside of the chunk",
+                    x, y, z
+                )
+            }
+            Self::Unreachable(id) => {
+                write!(
+                    f,
+                    "WFC Error: the walkable cells of {:?} are not connected",
+                    id
+                )
+            }
+            Self::NeighborsChanged => {
+                write!(
+                    f,
+                    "WFC Error: the neighbors changed while the chunk was generated"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for WfcError {}
+
+// fails with TooManyTiles if one of the ids does not fit into a tile mask
+pub fn check_tile_ids(ids: impl IntoIterator<Item = TileID>) -> Result<(), WfcError> {
+    let count = ids
+        .into_iter()
+        .map(|id| id.0 as usize + 1)
+        .max()
+        .unwrap_or(0);
+    if count > MAX_TILES {
+        return Err(WfcError::TooManyTiles(count));
+    }
+    Ok(())
+}
+
+impl ChunkBuilder {
+    pub fn new(id: ChunkId, dims: ChunkDims) -> Self {
+        Self {
+            origin: id.origin(&dims),
+            id,
+            dims,
+            ..default()
+        }
+    }
+
+    // A chunk sized block of cells starting at the given world tile coordinates, it can overlap
+    // several chunks and is written back into them with build_into.
+    pub fn block(origin: IVec2, dims: ChunkDims) -> Self {
+        let size = dims.size() as i32;
+        Self {
+            id: ChunkId::new(origin.x.div_euclid(size), origin.y.div_euclid(size)),
+            origin,
+            dims,
+            ..default()
+        }
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = ChaCha8Rng::seed_from_u64(seed);
+        self
+    }
+
+    pub fn add_rule_set(mut self, set: AdjRuleSet) -> Self {
+        self.rules = set.0;
+        self
+    }
+
+    // Constrains the border cells by the tiles right outside of them that are already in the
+    // world map, so the result joins legally with the chunks around it.
+    pub fn with_neighbors(mut self, world_map: &WorldMap) -> Self {
+        for pos in 0..self.dims.volume() {
+            for dir in [Dir::Forward, Dir::Backward, Dir::Left, Dir::Right] {
+                if self.neighbor(pos, dir).is_some() {
+                    continue;
+                }
+                let outside = self.world_pos(pos) + dir.to_vec3().as_ivec3();
+                if let Some(tile) = world_map.get_world_tile(outside) {
+                    self.border.push((pos, dir, tile));
+                }
+            }
+        }
+        self
+    }
+
+    // Restricts the cell to a single tile, for example to keep the ground flat where a base spawns.
+    pub fn pin(self, cell: UVec3, tile: TileID) -> Self {
+        self.pin_set(cell, TileMask::single(tile))
+    }
+
+    // Restricts the cell to the given tiles, pins of the same cell are intersected. The pins are
+    // propagated before the first collapse, so they can make build fail with a contradiction, or
+    // with PinOutside for a cell outside of the chunk.
+    pub fn pin_set(mut self, cell: UVec3, tiles: TileMask) -> Self {
+        self.pins.push((cell, tiles));
+        self
+    }
+
+    pub fn with_count(mut self, count: TileCount) -> Self {
+        self.counts.push(count);
+        self
+    }
+
+    // Only accept chunks where every walkable cell can be reached from every other one. Cells
+    // on the same level are connected if they are next to each other, ramps connect the levels.
+    // The paths have to stay within the chunk, the connections between chunks are not checked.
+    pub fn with_reachability(mut self) -> Self {
+        self.reachability = true;
+        self
+    }
+
+    // Walkable tiles are only placed on the levels the height field allows at their world
+    // position, the other tiles fill the terrain above and below.
+    pub fn with_height_field(mut self, height_field: HeightField) -> Self {
+        self.height_field = Some(height_field.with_height(self.dims.height()));
+        self
+    }
+
+    // The weights of the tiles are taken from the field instead of Tile::weight.
+    pub fn with_weight_field(mut self, weight_field: Arc<dyn WeightField>) -> Self {
+        self.weight_field = Some(weight_field);
+        self
+    }
+
+    pub fn with_selector(mut self, selector: CellSelector) -> Self {
+        self.selector = selector;
+        self
+    }
+
+    pub fn with_limits(mut self, limits: SolverLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn build(self, tiles: &Tiles) -> Result<Chunk, WfcError> {
+        self.build_with_stats(tiles).map(|(chunk, _)| chunk)
+    }
+
+    pub fn build_with_stats(mut self, tiles: &Tiles) -> Result<(Chunk, WfcStats), WfcError> {
+        let _span = info_span!("wfc_build", x = self.id.x(), z = self.id.z()).entered();
+        while self.step(tiles)? != WfcStep::Finished {}
+        let stats = self.stats.clone();
+        debug!(?stats, "WFC: built chunk {:?}", self.id);
+        Ok((self.finish(), stats))
+    }
+
+    // Solves the cells and overwrites them in every loaded chunk they fall into, cells of chunks
+    // that are not loaded are dropped. Returns the chunks that were changed.
+    pub fn build_into(
+        mut self,
+        tiles: &Tiles,
+        world_map: &mut WorldMap,
+    ) -> Result<Vec<ChunkId>, WfcError> {
+        let _span = info_span!("wfc_build_into", x = self.origin.x, z = self.origin.y).entered();
+        while self.step(tiles)? != WfcStep::Finished {}
+        let mut changed: Vec<ChunkId> = vec![];
+        for pos in 0..self.dims.volume() {
+            let Some(id) = world_map.set_world_tile(self.world_pos(pos), self.wave[pos].first())
+            else {
+                continue;
+            };
+            if !changed.contains(&id) {
+                changed.push(id);
+            }
+        }
+        Ok(changed)
+    }
+
+    // Collapses a single cell and propagates it, undoing earlier collapses on a contradiction.
+    // Call it until it returns WfcStep::Finished, the same seed gives the same steps as build.
+    pub fn step(&mut self, tiles: &Tiles) -> Result<WfcStep, WfcError> {
+        if !self.initialized {
+            self.restarts = 0;
+            self.stats = WfcStats::default();
+            self.timed_init(tiles)?;
+        }
+        // a cell whose tile was just ruled out is tried again with a different tile
+        let start = Instant::now();
+        let selected = self
+            .retry
+            .take()
+            .filter(|&pos| self.wave[pos].len() > 1)
+            .or_else(|| self.select_cell());
+        self.stats.select_time += start.elapsed();
+        let Some(pos) = selected else {
+            return Ok(WfcStep::Finished);
+        };
+
+        let start = Instant::now();
+        let _span = trace_span!("wfc_collapse", pos).entered();
+        let trail_len = self.trail.len();
+        let tile = self.collapse(pos);
+        self.decisions.push(Decision {
+            pos,
+            tile,
+            trail_len,
+        });
+        let propagated = self.propagate();
+        self.stats.collapses += 1;
+        self.stats.collapse_time += start.elapsed();
+        let contradiction = match propagated {
+            Ok(_) => {
+                let shrunk = self.trail[trail_len..].iter().map(|&(pos, _)| pos);
+                let shrunk = self.cells(shrunk);
+                if self.decisions.len() > self.limits.max_depth {
+                    self.commit_oldest();
+                }
+                return Ok(WfcStep::Collapsed {
+                    cell: self.cell(pos),
+                    tile,
+                    shrunk,
+                });
+            }
+            Err(e) => e,
+        };
+
+        let start = Instant::now();
+        let mut changed = vec![];
+        let backtracked = self.backtrack(contradiction, &mut changed);
+        self.stats.backtrack_time += start.elapsed();
+        match backtracked {
+            Ok((pos, tile)) => {
+                self.retry = Some(pos);
+                Ok(WfcStep::Backtracked {
+                    cell: self.cell(pos),
+                    tile,
+                    changed: self.cells(changed.into_iter()),
+                })
+            }
+            Err(_) if self.restarts < self.limits.max_restarts => {
+                self.restarts += 1;
+                self.stats.restarts += 1;
+                self.timed_init(tiles)?;
+                Ok(WfcStep::Restarted)
+            }
+            Err(WfcError::BacktrackLimit { backtracks, .. }) => Err(WfcError::BacktrackLimit {
+                backtracks,
+                restarts: self.restarts,
+            }),
+            Err(e) => Err(e),
+        }
+    }
+
+    // The remaining tiles of a cell, for tools that visualize the generation
+    pub fn superposition(&self, cell: UVec3) -> TileMask {
+        let pos = self
+            .dims
+            .index(cell.x as usize, cell.y as usize, cell.z as usize);
+        self.wave.get(pos).copied().unwrap_or_default()
+    }
+
+    // What the solver did so far, the stats start over with the first step.
+    pub fn stats(&self) -> &WfcStats {
+        &self.stats
+    }
+
+    // Cells that are not collapsed yet, for example when stopping early, are None.
+    pub fn finish(self) -> Chunk {
+        let tiles = self
+            .wave
+            .iter()
+            .map(|mask| if mask.len() == 1 { mask.first() } else { None })
+            .collect();
+        Chunk {
+            id: self.id,
+            dims: self.dims,
+            tiles,
+        }
+    }
+
+    fn timed_init(&mut self, tiles: &Tiles) -> Result<(), WfcError> {
+        let _span = debug_span!("wfc_init").entered();
+        let start = Instant::now();
+        let result = self.init(tiles);
+        self.stats.init_time += start.elapsed();
+        result
+    }
+
+    fn init(&mut self, tiles: &Tiles) -> Result<(), WfcError> {
+        if self.rules.is_empty() {
+            return Err(WfcError::EmptyRuleSet);
+        }
+        // the neighbors in the rules are masks already, only the tiles themselves can be too big
+        check_tile_ids(self.rules.keys().copied())?;
+        for id in self.rules.keys() {
+            if tiles.0.get(id).is_none_or(|tile| tile.weight == 0) {
+                return Err(WfcError::MissingWeight(*id));
+            }
+        }
+        self.trail.clear();
+        self.decisions.clear();
+        self.backtracks = 0;
+        self.retry = None;
+        self.init_compatible();
+        self.wave = vec![TileMask::EMPTY; self.dims.volume()];
+        for y in 0..self.dims.height() {
+            let mask: TileMask = self
+                .rules
+                .keys()
+                .filter(|&id| {
+                    let tile: &Tile = &tiles.0[id];
+                    let Some(ref range) = tile.y_level else {
+                        return true;
+                    };
+                    range.contains(&y)
+                })
+                .cloned()
+                .collect();
+            for x in 0..self.dims.size() {
+                for z in 0..self.dims.size() {
+                    let index = self.dims.index(x, y, z);
+                    self.wave[index] = mask;
+                }
+            }
+        }
+        for &(pos, dir, outside) in self.border.iter() {
+            // tiles of another rule set can not constrain this one
+            if let Some(compatible) = self.compatible.get(outside.0 as usize) {
+                self.wave[pos] &= compatible[dir.opposite() as usize];
+            }
+        }
+        for &(cell, tiles) in self.pins.iter() {
+            let (x, y, z) = (cell.x as usize, cell.y as usize, cell.z as usize);
+            if !self.dims.contains(x, y, z) {
+                return Err(WfcError::pin_outside(cell));
+            }
+            self.wave[self.dims.index(x, y, z)] &= tiles;
+        }
+        self.init_walkable(tiles);
+        if let Some(ref height_field) = self.height_field {
+            for pos in 0..self.dims.volume() {
+                let world_pos = self.world_pos(pos);
+                if !height_field.allows(world_pos.x, world_pos.y as usize, world_pos.z) {
+                    self.wave[pos] &= !self.walkable;
+                }
+            }
+        }
+        self.init_weights(tiles);
+        if let Some(pos) = (0..self.dims.volume()).find(|&pos| self.wave[pos].is_empty()) {
+            return Err(WfcError::contradiction(self.cell(pos)));
+        }
+        self.init_counts(tiles);
+        self.init_supports();
+        self.propagate()?;
+        // the initial wave can never be undone
+        self.trail.clear();
+        self.init_queue();
+        self.initialized = true;
+        Ok(())
+    }
+
+    fn init_compatible(&mut self) {
+        self.tile_count = self
+            .rules
+            .keys()
+            .map(|id| id.0 as usize + 1)
+            .max()
+            .unwrap_or(0);
+        self.compatible = vec![[TileMask::EMPTY; 6]; self.tile_count];
+        for (&id, rule) in self.rules.iter() {
+            for dir in Dir::iter() {
+                let compatible = rule.mask(dir).iter().filter(|other| {
+                    self.rules
+                        .get(other)
+                        .is_some_and(|rule| rule.mask(dir.opposite()).contains(id))
+                });
+                self.compatible[id.0 as usize][dir as usize] = compatible.collect();
+            }
+        }
+    }
+
+    fn init_weights(&mut self, tiles: &Tiles) {
+        let len = self.tile_count;
+        self.weights = vec![0; self.dims.volume() * len];
+        self.weight_sums = vec![0; self.dims.volume()];
+        self.weight_log_sums = vec![0; self.dims.volume()];
+        for pos in 0..self.dims.volume() {
+            let world_pos = self.world_pos(pos);
+            let mask = self.wave[pos];
+            let cell: Vec<&Tile> = mask.iter().map(|id| &tiles.0[&id]).collect();
+            let weights: Vec<f64> = match self.weight_field {
+                Some(ref field) => {
+                    let weights = field.weights(world_pos, &cell).into_iter();
+                    weights.map(f64::from).collect()
+                }
+                None => cell.iter().map(|tile| tile.weight as f64).collect(),
+            };
+            for (id, weight) in mask.iter().zip(weights) {
+                if weight.is_nan() || weight <= 0.0 {
+                    self.wave[pos].remove(id);
+                    continue;
+                }
+                self.weights[pos * len + id.0 as usize] = fixed_weight(weight);
+                self.add_weight(pos, id);
+            }
+        }
+    }
+
+    fn weight(&self, pos: usize, tile: TileID) -> u64 {
+        self.weights[pos * self.tile_count + tile.0 as usize]
+    }
+
+    fn init_counts(&mut self, tiles: &Tiles) {
+        self.count_states.clear();
+        for count in self.counts.iter() {
+            let mask = self
+                .rules
+                .keys()
+                .filter(|&id| tiles.0[id].prototype == count.prototype)
+                .copied()
+                .collect();
+            let mut state = CountState {
+                tiles: mask,
+                changed: true,
+                fixed: 0,
+                possible: 0,
+            };
+            for pos in (0..self.dims.volume()).filter(|&pos| count.contains(self.cell(pos))) {
+                state.fixed += state.is_fixed(self.wave[pos]) as usize;
+                state.possible += state.is_possible(self.wave[pos]) as usize;
+            }
+            self.count_states.push(state);
+        }
+    }
+
+    // keep the tile counts up to date when the superposition of the cell changes
+    fn update_counts(&mut self, pos: usize, before: TileMask, after: TileMask) {
+        let cell = self.cell(pos);
+        for (count, state) in self.counts.iter().zip(self.count_states.iter_mut()) {
+            if !count.contains(cell) {
+                continue;
+            }
+            let fixed = (state.is_fixed(before), state.is_fixed(after));
+            let possible = (state.is_possible(before), state.is_possible(after));
+            state.fixed = state.fixed + fixed.1 as usize - fixed.0 as usize;
+            state.possible = state.possible + possible.1 as usize - possible.0 as usize;
+            state.changed |= fixed.0 != fixed.1 || possible.0 != possible.1;
+        }
+    }
+
+    // Fails if a tile count can not be met anymore. Once a count is at its limit the undecided
+    // cells of the region are decided: they lose the tiles of the prototype when the maximum is
+    // reached, or keep only them when the minimum is. Returns if any tile was removed.
+    fn enforce_counts(&mut self) -> Result<bool, WfcError> {
+        for i in 0..self.counts.len() {
+            if self.counts[i].clusters {
+                if self.enforce_clusters(i)? {
+                    return Ok(true);
+                }
+                continue;
+            }
+            let count = &self.counts[i];
+            let state = &self.count_states[i];
+            if state.fixed > count.max || state.possible < count.min {
+                return Err(WfcError::TileCount(count.prototype.clone()));
+            }
+            // every cell of the region is decided
+            if state.fixed == state.possible {
+                continue;
+            }
+            let remove = if state.fixed == count.max {
+                state.tiles
+            } else if state.possible == count.min {
+                !state.tiles
+            } else {
+                continue;
+            };
+            let region: Vec<usize> = (0..self.dims.volume())
+                .filter(|&pos| count.contains(self.cell(pos)))
+                .collect();
+            for pos in region {
+                let mask = self.wave[pos];
+                if self.count_states[i].is_fixed(mask) || !self.count_states[i].is_possible(mask) {
+                    continue;
+                }
+                // the cell holds tiles of both kinds, so this never empties it
+                for tile in (mask & remove).iter() {
+                    self.remove(pos, tile)?;
+                }
+            }
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    // The clusters of a count can not merge beyond the groups of cells that may hold the
+    // prototype, so every group with a fixed cell holds at least one cluster. Fails if those are
+    // more than the maximum, or fewer than the minimum once the region is decided. At the maximum
+    // the groups without a fixed cell lose the prototype, each would start another cluster.
+    // Returns if any tile was removed.
+    fn enforce_clusters(&mut self, i: usize) -> Result<bool, WfcError> {
+        let state = &mut self.count_states[i];
+        if !std::mem::take(&mut state.changed) {
+            return Ok(false);
+        }
+        let decided = state.fixed == state.possible;
+        let clusters = self.label_clusters(i);
+        let count = &self.counts[i];
+        if clusters > count.max || (decided && clusters < count.min) {
+            return Err(WfcError::TileCount(count.prototype.clone()));
+        }
+        if clusters < count.max || self.cluster_open.is_empty() {
+            return Ok(false);
+        }
+        let open = std::mem::take(&mut self.cluster_open);
+        let tiles = self.count_states[i].tiles;
+        let result = open.iter().try_for_each(|&pos| {
+            // the cell is not fixed, so it keeps tiles of other prototypes
+            for tile in (self.wave[pos] & tiles).iter() {
+                self.remove(pos, tile)?;
+            }
+            Ok(())
+        });
+        self.cluster_open = open;
+        result.map(|_| true)
+    }
+
+    // Groups the cells of the region of the count that may hold its prototype by the faces they
+    // share. Returns the number of groups with a fixed cell, the cells of the other groups are
+    // left in cluster_open.
+    fn label_clusters(&mut self, i: usize) -> usize {
+        let mut seen = std::mem::take(&mut self.cluster_seen);
+        let mut stack = std::mem::take(&mut self.cluster_stack);
+        let mut open = std::mem::take(&mut self.cluster_open);
+        seen.clear();
+        seen.resize(self.dims.volume(), false);
+        open.clear();
+        let (count, state) = (&self.counts[i], &self.count_states[i]);
+        let member =
+            |pos: usize| count.contains(self.cell(pos)) && state.is_possible(self.wave[pos]);
+        let mut clusters = 0;
+        for start in 0..self.dims.volume() {
+            if seen[start] || !member(start) {
+                continue;
+            }
+            seen[start] = true;
+            stack.push(start);
+            let first = open.len();
+            let mut fixed = false;
+            while let Some(pos) = stack.pop() {
+                open.push(pos);
+                fixed |= state.is_fixed(self.wave[pos]);
+                for next in Dir::iter().filter_map(|dir| self.neighbor(pos, dir)) {
+                    if !seen[next] && member(next) {
+                        seen[next] = true;
+                        stack.push(next);
+                    }
+                }
+            }
+            if fixed {
+                clusters += 1;
+                open.truncate(first);
+            }
+        }
+        self.cluster_seen = seen;
+        self.cluster_stack = stack;
+        self.cluster_open = open;
+        clusters
+    }
+
+    fn init_walkable(&mut self, tiles: &Tiles) {
+        let ids = self.rules.keys();
+        (self.walkable, self.ramps) = walk_masks(ids.map(|id| (*id, &tiles.0[id])));
+        self.walkable_changed = true;
+    }
+
+    // Fails if two cells that have to be walkable can not be connected anymore, otherwise removes
+    // the walkable tiles from every cell that can not be connected to them. Returns if any tile
+    // was removed.
+    fn enforce_reachability(&mut self) -> Result<bool, WfcError> {
+        if !self.reachability || !self.walkable_changed {
+            return Ok(false);
+        }
+        self.walkable_changed = false;
+
+        // the buffers are reused, the check runs after most propagations
+        let mut area = std::mem::take(&mut self.walk_areas);
+        let mut stack = std::mem::take(&mut self.walk_stack);
+        area.clear();
+        area.resize(self.dims.volume(), usize::MAX);
+        stack.clear();
+        let result = self
+            .label_walk_areas(&mut area, &mut stack)
+            .and_then(|walkable_area| {
+                let Some(walkable_area) = walkable_area else {
+                    return Ok(false);
+                };
+                let mut removed = false;
+                for (pos, &area) in area.iter().enumerate() {
+                    if area == usize::MAX || area == walkable_area {
+                        continue;
+                    }
+                    // the cell is not in the walkable area, so it has tiles that are not walkable
+                    // left
+                    for tile in (self.wave[pos] & self.walkable).iter() {
+                        self.remove(pos, tile)?;
+                        removed = true;
+                    }
+                }
+                Ok(removed)
+            });
+        self.walk_areas = area;
+        self.walk_stack = stack;
+        result
+    }
+
+    // Labels the cells that may be walkable by the connected area they belong to, cells that can
+    // not be walkable keep usize::MAX. Returns the area of the cells that have to be walkable.
+    fn label_walk_areas(
+        &self,
+        area: &mut [usize],
+        stack: &mut Vec<usize>,
+    ) -> Result<Option<usize>, WfcError> {
+        let mut walkable_area = None;
+        for start in 0..self.dims.volume() {
+            if area[start] != usize::MAX || !self.may_walk(start) {
+                continue;
+            }
+            area[start] = start;
+            stack.push(start);
+            while let Some(pos) = stack.pop() {
+                if self.must_walk(pos) {
+                    match walkable_area {
+                        None => walkable_area = Some(start),
+                        Some(other) if other != start => {
+                            stack.clear();
+                            return Err(WfcError::contradiction(self.cell(pos)));
+                        }
+                        Some(_) => (),
+                    }
+                }
+                for next in self.walk_neighbors(pos) {
+                    if area[next] == usize::MAX {
+                        area[next] = start;
+                        stack.push(next);
+                    }
+                }
+            }
+        }
+        Ok(walkable_area)
+    }
+
+    fn may_walk(&self, pos: usize) -> bool {
+        !(self.wave[pos] & self.walkable).is_empty()
+    }
+
+    fn must_walk(&self, pos: usize) -> bool {
+        (self.wave[pos] & !self.walkable).is_empty()
+    }
+
+    // If the walkable areas can change when the tiles of a cell shrink from before to after: the
+    // cell can no longer be walkable, has to be walkable now or lost a ramp.
+    fn walk_changed(&self, before: TileMask, after: TileMask) -> bool {
+        let lost = |tiles: TileMask| !(before & tiles).is_empty() && (after & tiles).is_empty();
+        lost(self.walkable) || lost(!self.walkable) || self.ramps.iter().any(|&ramp| lost(ramp))
+    }
+
+    // cells that may be walkable and may be connected to the cell
+    fn walk_neighbors(&self, pos: usize) -> impl Iterator<Item = usize> + '_ {
+        [Dir::Forward, Dir::Backward, Dir::Left, Dir::Right]
+            .into_iter()
+            .flat_map(move |dir| {
+                let next = self.neighbor(pos, dir);
+                // a ramp in this cell leads up
+                let up = next
+                    .filter(|_| !(self.wave[pos] & self.ramps[dir as usize]).is_empty())
+                    .and_then(|next| self.neighbor(next, Dir::Up));
+                // a ramp in the cell below leads up to this one
+                let down = self
+                    .neighbor(pos, Dir::Down)
+                    .and_then(|below| self.neighbor(below, dir.opposite()))
+                    .filter(|&below| !(self.wave[below] & self.ramps[dir as usize]).is_empty());
+                [next, up, down]
+            })
+            .flatten()
+            .filter(|&next| self.may_walk(next))
+    }
+
+    fn init_queue(&mut self) {
+        self.queue.clear();
+        self.touched.clear();
+        self.versions = vec![0; self.dims.volume()];
+        for pos in 0..self.dims.volume() {
+            self.enqueue(pos);
+        }
+    }
+
+    fn init_supports(&mut self) {
+        let len = self.tile_count;
+        self.supports = vec![[0; 6]; self.dims.volume() * len];
+        self.pending.clear();
+        for pos in 0..self.dims.volume() {
+            for dir in Dir::iter() {
+                let Some(neighbor_pos) = self.neighbor(pos, dir) else {
+                    continue;
+                };
+                // counted for every tile, so removals never have to check the wave first
+                for tile in 0..len {
+                    let allowed = self.compatible[tile][dir as usize] & self.wave[neighbor_pos];
+                    let count = allowed.len();
+                    self.supports[pos * len + tile][dir as usize] = count as u16;
+                    let id = TileID(tile as u32);
+                    if count == 0 && self.wave[pos].contains(id) {
+                        self.pending.push((pos, id));
+                    }
+                }
+            }
+        }
+    }
+
+    // the entropy does not depend on the scale of the weights, so the fixed point sums are used
+    // as they are
+    fn shannon_entropy(&self, pos: usize) -> f32 {
+        let sum = self.weight_sums[pos] as f64;
+        (f64::log2(sum) - self.weight_log_sums[pos] as f64 / sum) as f32
+    }
+
+    fn add_weight(&mut self, pos: usize, tile: TileID) {
+        let weight = self.weight(pos, tile);
+        self.weight_sums[pos] += weight;
+        self.weight_log_sums[pos] += weight_log(weight);
+    }
+
+    fn sub_weight(&mut self, pos: usize, tile: TileID) {
+        let weight = self.weight(pos, tile);
+        self.weight_sums[pos] -= weight;
+        self.weight_log_sums[pos] -= weight_log(weight);
+    }
+
+    // the queue entry of the cell is updated the next time a cell is selected
+    fn touch(&mut self, pos: usize) {
+        if self.touched.last() != Some(&pos) {
+            self.touched.push(pos);
+        }
+    }
+
+    // push the cell with its current priority, older entries of it are outdated from now on
+    fn enqueue(&mut self, pos: usize) {
+        self.versions[pos] = self.versions[pos].wrapping_add(1);
+        let len = self.wave[pos].len();
+        if len <= 1 {
+            return;
+        }
+        let priority = match self.selector {
+            CellSelector::MinRemainingValues => len as f32,
+            // noise well below the difference of two distinct entropies breaks ties randomly
+            CellSelector::Entropy => self.shannon_entropy(pos) + self.rng.gen_range(0.0..1e-4),
+            CellSelector::Scanline => 0.0,
+            CellSelector::Distance(seed) => {
+                (self.cell(pos).as_ivec3() - seed.as_ivec3()).length_squared() as f32
+            }
+        };
+        self.queue.push(Candidate {
+            priority,
+            pos,
+            version: self.versions[pos],
+        });
+    }
+
+    fn random_by_weight(&mut self, pos: usize) -> Option<TileID> {
+        let sum = self.weight_sums[pos];
+        if sum == 0 {
+            return None;
+        }
+        let random = self.rng.gen_range(0..sum);
+        let mut cursor = 0;
+        self.wave[pos].iter().find(|&id| {
+            cursor += self.weight(pos, id);
+            cursor > random
+        })
+    }
+
+    // collapse superposition in random element
+    fn collapse(&mut self, pos: usize) -> TileID {
+        let superpos = self.wave[pos];
+        let tile = self
+            .random_by_weight(pos)
+            .expect("only cells with tiles left are collapsed");
+        for other in superpos.iter().filter(|&id| id != tile) {
+            // the chosen tile stays, so this can not empty the cell
+            let _ = self.remove(pos, other);
+        }
+        tile
+    }
+
+    // remove a tile from the superposition at pos and update the supports of its neighbors
+    fn remove(&mut self, pos: usize, tile: TileID) -> Result<(), WfcError> {
+        if self.wave[pos] == TileMask::single(tile) {
+            return Err(WfcError::contradiction(self.cell(pos)));
+        }
+        let before = self.wave[pos];
+        self.wave[pos].remove(tile);
+        self.update_counts(pos, before, self.wave[pos]);
+        // restoring tiles only connects areas, so only removals need a new check
+        if self.reachability && !self.walkable_changed {
+            self.walkable_changed = self.walk_changed(before, self.wave[pos]);
+        }
+        self.trail.push((pos, tile));
+        self.sub_weight(pos, tile);
+        self.touch(pos);
+
+        let len = self.tile_count;
+        for dir in Dir::iter() {
+            let Some(neighbor_pos) = self.neighbor(pos, dir) else {
+                continue;
+            };
+            // the neighbor sees this cell in the opposite direction
+            let back = dir.opposite() as usize;
+            for other in self.compatible[tile.0 as usize][dir as usize].iter() {
+                let support = &mut self.supports[neighbor_pos * len + other.0 as usize][back];
+                *support -= 1;
+                if *support == 0 {
+                    self.pending.push((neighbor_pos, other));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // put back all tiles removed since the trail had the given length
+    fn undo(&mut self, trail_len: usize) {
+        let len = self.tile_count;
+        while self.trail.len() > trail_len {
+            let (pos, tile) = self.trail.pop().unwrap();
+            let before = self.wave[pos];
+            self.wave[pos].insert(tile);
+            self.update_counts(pos, before, self.wave[pos]);
+            self.add_weight(pos, tile);
+            self.touch(pos);
+            for dir in Dir::iter() {
+                let Some(neighbor_pos) = self.neighbor(pos, dir) else {
+                    continue;
+                };
+                let back = dir.opposite() as usize;
+                for other in self.compatible[tile.0 as usize][dir as usize].iter() {
+                    self.supports[neighbor_pos * len + other.0 as usize][back] += 1;
+                }
+            }
+        }
+    }
+
+    // propagate all pending removals until every remaining tile is supported in every direction
+    // and the tile counts can still be met
+    fn propagate(&mut self) -> Result<(), WfcError> {
+        let trail_len = self.trail.len();
+        let result = self.propagate_pending();
+        self.stats.removals += self.trail.len() - trail_len;
+        if result.is_err() {
+            self.stats.contradictions += 1;
+        }
+        result
+    }
+
+    fn propagate_pending(&mut self) -> Result<(), WfcError> {
+        loop {
+            while let Some((pos, tile)) = self.pending.pop() {
+                if !self.wave[pos].contains(tile) {
+                    continue;
+                }
+                if let Err(e) = self.remove(pos, tile) {
+                    self.pending.clear();
+                    return Err(e);
+                }
+            }
+            let enforced = self
+                .enforce_counts()
+                .and_then(|counted| Ok(counted || self.enforce_reachability()?));
+            match enforced {
+                Ok(true) => (),
+                Ok(false) => return Ok(()),
+                Err(e) => {
+                    self.pending.clear();
+                    return Err(e);
+                }
             }
         }
-        Self { id, tiles }
     }
 
-    pub fn id(&self) -> ChunkId {
-        self.id.clone()
+    fn cell(&self, pos: usize) -> UVec3 {
+        let (x, y, z) = self.dims.from_index(pos);
+        UVec3::new(x as u32, y as u32, z as u32)
     }
 
-    pub fn get_tile(&self, x: usize, y: usize, z: usize) -> Option<TileID> {
-        self.tiles[get_index(x, y, z)]
+    // sorted cells without duplicates
+    fn cells(&self, positions: impl Iterator<Item = usize>) -> Vec<UVec3> {
+        let mut positions: Vec<usize> = positions.collect();
+        positions.sort();
+        positions.dedup();
+        positions.into_iter().map(|pos| self.cell(pos)).collect()
     }
 
-    pub fn pos(&self) -> Vec3 {
-        let x = self.id.x() as f32;
-        let z = self.id.z() as f32;
-        let size = CHUNK_SIZE as f32;
-        Vec3::new(x, 0.0, z) * size
+    fn world_pos(&self, pos: usize) -> IVec3 {
+        let cell = self.cell(pos).as_ivec3();
+        IVec3::new(self.origin.x + cell.x, cell.y, self.origin.y + cell.z)
     }
-}
 
-// Chunk Generatorion
+    fn neighbor(&self, pos: usize, dir: Dir) -> Option<usize> {
+        let (x, y, z) = self.dims.from_index(pos);
+        let (x_off, y_off, z_off): (isize, isize, isize) = match dir {
+            Dir::Forward => (0, 0, -1),
+            Dir::Backward => (0, 0, 1),
+            Dir::Left => (-1, 0, 0),
+            Dir::Right => (1, 0, 0),
+            Dir::Up => (0, 1, 0),
+            Dir::Down => (0, -1, 0),
+        };
+        let x_res = if (x == 0 && x_off == -1) || (x == self.dims.size() - 1 && x_off == 1) {
+            return None;
+        } else {
+            (x as isize + x_off) as usize
+        };
 
-#[derive(Debug, Clone)]
-enum WaveState {
-    Collapsed(TileID),
-    Superpos(Vec<TileID>),
-}
+        let y_res = if (y == 0 && y_off == -1) || (y == self.dims.height() - 1 && y_off == 1) {
+            return None;
+        } else {
+            (y as isize + y_off) as usize
+        };
 
-impl WaveState {
-    fn is_collapsed(&self) -> bool {
-        match self {
-            Self::Collapsed(_) => true,
-            Self::Superpos(_) => false,
+        let z_res = if (z == 0 && z_off == -1) || (z == self.dims.size() - 1 && z_off == 1) {
+            return None;
+        } else {
+            (z as isize + z_off) as usize
+        };
+
+        Some(self.dims.index(x_res, y_res, z_res))
+    }
+
+    // the open cell the selector prefers, ties go to the lower index, None once every cell is
+    // collapsed
+    fn select_cell(&mut self) -> Option<usize> {
+        let mut touched = std::mem::take(&mut self.touched);
+        touched.sort_unstable();
+        touched.dedup();
+        for &pos in touched.iter() {
+            self.enqueue(pos);
+        }
+        touched.clear();
+        self.touched = touched;
+        // outdated entries pile up over long backtracking runs
+        if self.queue.len() > 4 * self.dims.volume() {
+            self.init_queue();
+        }
+
+        while let Some(candidate) = self.queue.peek() {
+            let pos = candidate.pos;
+            if candidate.version == self.versions[pos] && self.wave[pos].len() > 1 {
+                return Some(pos);
+            }
+            self.queue.pop();
+        }
+        None
+    }
+
+    // undo decisions until ruling out the tile of the last one leaves a consistent wave, the
+    // contradiction is returned once there is nothing left to undo
+    fn backtrack(
+        &mut self,
+        mut contradiction: WfcError,
+        changed: &mut Vec<usize>,
+    ) -> Result<(usize, TileID), WfcError> {
+        loop {
+            if self.backtracks >= self.limits.max_backtracks {
+                return Err(WfcError::BacktrackLimit {
+                    backtracks: self.backtracks,
+                    restarts: 0,
+                });
+            }
+            let Some(decision) = self.decisions.pop() else {
+                return Err(contradiction);
+            };
+            self.backtracks += 1;
+            self.stats.backtracks += 1;
+            self.pending.clear();
+            changed.extend(self.trail[decision.trail_len..].iter().map(|&(pos, _)| pos));
+            self.undo(decision.trail_len);
+            let result = self
+                .remove(decision.pos, decision.tile)
+                .and_then(|_| self.propagate());
+            changed.extend(self.trail[decision.trail_len..].iter().map(|&(pos, _)| pos));
+            match result {
+                Ok(_) => return Ok((decision.pos, decision.tile)),
+                Err(e) => contradiction = e,
+            }
+        }
+    }
+
+    // forget the oldest decision, it can no longer be undone
+    fn commit_oldest(&mut self) {
+        self.decisions.remove(0);
+        let committed = self
+            .decisions
+            .first()
+            .map_or(self.trail.len(), |d| d.trail_len);
+        self.trail.drain(..committed);
+        for decision in self.decisions.iter_mut() {
+            decision.trail_len -= committed;
         }
     }
 }
 
-pub struct ChunkBuilder {
-    id: ChunkId,
-    wave: Vec<WaveState>,
-    rules: HashMap<TileID, AdjacencyRules>,
-    rng: ThreadRng,
+impl CountState {
+    fn is_fixed(&self, mask: TileMask) -> bool {
+        !mask.is_empty() && (mask & !self.tiles).is_empty()
+    }
+
+    fn is_possible(&self, mask: TileMask) -> bool {
+        !(mask & self.tiles).is_empty()
+    }
 }
 
-impl Default for ChunkBuilder {
-    fn default() -> Self {
-        Self {
-            id: ChunkId::default(),
-            wave: vec![],
-            rules: HashMap::default(),
-            rng: rand::thread_rng(),
-        }
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
     }
 }
 
-struct WaveError(&'static str);
+impl Eq for Candidate {}
 
-impl Display for WaveError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "WFC Error: {}", self.0)
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
     }
 }
 
-impl ChunkBuilder {
-    pub fn new(id: ChunkId) -> Self {
-        Self { id, ..default() }
+impl Ord for Candidate {
+    // reversed, BinaryHeap is a max heap
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .priority
+            .total_cmp(&self.priority)
+            .then(other.pos.cmp(&self.pos))
     }
+}
 
-    pub fn add_rule_set(mut self, set: AdjRuleSet) -> Self {
-        self.rules = set.0;
-        self
+#[cfg(test)]
+mod tests {
+    use super::super::fixtures::{allowed, assert_world_is_legal, landscape};
+    use super::super::fixtures::{tile, tileset};
+    use super::*;
+
+    const DIMS: ChunkDims = ChunkDims::DEFAULT;
+
+    fn cell(pos: usize) -> UVec3 {
+        let (x, y, z) = DIMS.from_index(pos);
+        UVec3::new(x as u32, y as u32, z as u32)
     }
 
-    pub fn build(mut self, tiles: &Tiles) -> Chunk {
-        self.init(tiles);
-        if let Err(e) = self.branch(tiles) {
-            error!("{}", e);
+    // removes unsupported tiles one pass at a time until nothing changes anymore
+    fn brute_force(
+        builder: &ChunkBuilder,
+        mut domains: Vec<Vec<TileID>>,
+        rules: &AdjRuleSet,
+    ) -> Vec<Vec<TileID>> {
+        loop {
+            let mut changed = false;
+            for pos in 0..DIMS.volume() {
+                for tile in domains[pos].clone() {
+                    let supported = Dir::iter().all(|dir| {
+                        let Some(neighbor_pos) = builder.neighbor(pos, dir) else {
+                            return true;
+                        };
+                        domains[neighbor_pos]
+                            .iter()
+                            .any(|&other| allowed(rules, tile, other, dir))
+                    });
+                    if !supported {
+                        domains[pos].retain(|&id| id != tile);
+                        changed = true;
+                    }
+                }
+            }
+            if !changed {
+                return domains;
+            }
         }
-        // while !self.is_collapsed() {
-        //     if let Err(e) = self.iterate(tiles) {
-        //         error!("{}", e);
-        //         break;
-        //     }
-        // }
+    }
 
-        let mut tiles: Vec<Option<TileID>> = Vec::new();
-        for wave_state in self.wave {
-            let WaveState::Collapsed(tile) = wave_state else {
-                // panic!("wave function collapse should not fail");
-                tiles.push(None);
+    #[test]
+    fn test_propagation_matches_brute_force() {
+        let (tiles, rules) = landscape();
+        for seed in 0..8 {
+            let mut rng = ChaCha8Rng::seed_from_u64(seed);
+            let mut builder = ChunkBuilder::new(ChunkId::default(), DIMS)
+                .with_seed(seed)
+                .add_rule_set(rules.clone());
+
+            let mut result = builder.init(&tiles);
+            let initial: Vec<Vec<TileID>> = (0..DIMS.volume())
+                .map(|pos| {
+                    let (_, y, _) = DIMS.from_index(pos);
+                    let mut ids: Vec<TileID> = tiles
+                        .0
+                        .values()
+                        .filter(|tile| tile.y_level.as_ref().is_none_or(|r| r.contains(&y)))
+                        .map(|tile| tile.id)
+                        .collect();
+                    ids.sort();
+                    ids
+                })
+                .collect();
+            let mut decided = initial.clone();
+            for _ in 0..10 {
+                if result.is_err() {
+                    break;
+                }
+                let pos = rng.gen_range(0..DIMS.volume());
+                if builder.wave[pos].len() == 1 {
+                    continue;
+                }
+                decided[pos] = vec![builder.collapse(pos)];
+                result = builder.propagate();
+            }
 
+            let expected = brute_force(&builder, decided, &rules);
+            if result.is_err() {
+                assert!(
+                    expected.iter().any(|ids| ids.is_empty()),
+                    "seed {seed}: propagation found a contradiction the reference did not"
+                );
                 continue;
-            };
-            tiles.push(Some(tile));
+            }
+            for (pos, ids) in expected.iter().enumerate() {
+                assert_eq!(
+                    builder.wave[pos],
+                    ids.iter().cloned().collect(),
+                    "seed {seed}: wrong superposition at {:?}",
+                    DIMS.from_index(pos)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_removals_cascade() {
+        // tiles that only fit next to themselves, collapsing a single cell decides the rest
+        let (tiles, rules) = tileset(
+            vec![tile(0, "red"), tile(1, "blue")],
+            &[(0, 0), (1, 1)],
+            &[(0, 0), (1, 1)],
+        );
+        let mut builder = ChunkBuilder::new(ChunkId::default(), DIMS).add_rule_set(rules);
+        assert!(builder.init(&tiles).is_ok());
+        let pos = DIMS.index(0, 0, 0);
+        let tile = builder.collapse(pos);
+        assert!(builder.propagate().is_ok());
+        for pos in 0..DIMS.volume() {
+            assert_eq!(builder.wave[pos], TileMask::single(tile));
         }
+    }
 
-        Chunk { id: self.id, tiles }
+    #[test]
+    fn test_undo_restores_wave() {
+        let (tiles, rules) = landscape();
+        let mut builder = ChunkBuilder::new(ChunkId::default(), DIMS).add_rule_set(rules);
+        assert!(builder.init(&tiles).is_ok());
+        let wave = builder.wave.clone();
+        let supports = builder.supports.clone();
+
+        let pos = DIMS.index(5, 1, 7);
+        builder.collapse(pos);
+        let _ = builder.propagate();
+        assert_ne!(builder.wave, wave);
+        builder.undo(0);
+        assert_eq!(builder.wave, wave);
+        assert_eq!(builder.supports, supports);
     }
 
-    fn init(&mut self, tiles: &Tiles) {
-        self.wave = vec![WaveState::Superpos(vec![]); CHUNK_VOLUME];
-        for y in 0..CHUNK_HIGHT {
-            let ids: Vec<TileID> = self
-                .rules
-                .keys()
-                .filter(|&id| {
-                    let tile: &Tile = &tiles.0[id];
-                    let Some(ref range) = tile.y_level else {
-                        return true;
+    #[test]
+    fn test_solved_chunk_is_valid_and_reproducible() {
+        let (tiles, rules) = landscape();
+        for seed in 0..4 {
+            let build = || {
+                ChunkBuilder::new(ChunkId::default(), DIMS)
+                    .with_seed(seed)
+                    .add_rule_set(rules.clone())
+                    .build(&tiles)
+                    .unwrap()
+            };
+            let chunk = build();
+            assert_eq!(chunk.tiles, build().tiles, "seed {seed}: not reproducible");
+
+            let builder = ChunkBuilder::default();
+            for pos in 0..DIMS.volume() {
+                let tile = chunk.tiles[pos].unwrap();
+                for dir in Dir::iter() {
+                    let Some(neighbor_pos) = builder.neighbor(pos, dir) else {
+                        continue;
                     };
-                    range.contains(&y)
-                })
-                .cloned()
-                .collect();
-            for x in 0..CHUNK_SIZE {
-                for z in 0..CHUNK_SIZE {
-                    let index = get_index(x, y, z);
-                    self.wave[index] = WaveState::Superpos(ids.clone());
+                    let other = chunk.tiles[neighbor_pos].unwrap();
+                    assert!(allowed(&rules, tile, other, dir), "seed {seed}");
                 }
             }
         }
-        for y in 0..CHUNK_HIGHT {
-            for x in 0..CHUNK_SIZE {
-                for z in 0..CHUNK_SIZE {
-                    let index = get_index(x, y, z);
-                    self.propagate(index);
+    }
+
+    // the tiles a seed produces are part of the saved worlds, a change to the solver that changes
+    // them has to be deliberate
+    #[test]
+    fn test_seeded_chunk_is_stable() {
+        let (tiles, rules) = landscape();
+        let mut world_map = WorldMap::new(ChunkDims::new(4, 2));
+        let id = ChunkId::new(1, -2);
+        let ctx = ChunkContext::new(&tiles, &rules).with_seed(WorldSeed(42));
+        generate_chunk(&mut world_map, &id, ctx).unwrap();
+        let chunk = world_map.get(&id).unwrap();
+        let ids: Vec<u32> = chunk.tiles.iter().map(|tile| tile.unwrap().0).collect();
+        let expected = [
+            // the lower level
+            [0, 0, 1, 1, 1, 0, 0, 1, 0, 1, 4, 0, 1, 4, 0, 4],
+            // the upper level
+            [3, 3, 2, 2, 2, 2, 1, 2, 3, 2, 1, 1, 2, 1, 1, 1],
+        ];
+        assert_eq!(ids, expected.concat());
+    }
+
+    #[test]
+    fn test_backtrack_rules_out_the_tile() {
+        let (tiles, rules) = landscape();
+        let mut builder = ChunkBuilder::new(ChunkId::default(), DIMS).add_rule_set(rules);
+        assert!(builder.init(&tiles).is_ok());
+        let pos = builder.select_cell().unwrap();
+        let wave = builder.wave[pos];
+
+        let tile = builder.collapse(pos);
+        builder.decisions.push(Decision {
+            pos,
+            tile,
+            trail_len: 0,
+        });
+        let _ = builder.propagate();
+        assert_eq!(
+            builder.backtrack(WfcError::EmptyRuleSet, &mut vec![]),
+            Ok((pos, tile))
+        );
+        assert!(builder.decisions.is_empty());
+        assert!(builder.wave[pos].len() < wave.len());
+        assert!(!builder.wave[pos].contains(tile));
+    }
+
+    #[test]
+    fn test_build_errors() {
+        let (mut tiles, rules) = landscape();
+        let build = |rules: AdjRuleSet, tiles: &Tiles| {
+            ChunkBuilder::new(ChunkId::default(), DIMS)
+                .add_rule_set(rules)
+                .build(tiles)
+                .err()
+        };
+
+        assert_eq!(
+            build(AdjRuleSet(HashMap::new()), &tiles),
+            Some(WfcError::EmptyRuleSet)
+        );
+
+        // a tile that does not connect to anything
+        let mut lonely = rules.clone();
+        lonely.0.insert(TileID(0), AdjacencyRules::default());
+        lonely.0.retain(|&id, _| id == TileID(0));
+        assert!(matches!(
+            build(lonely, &tiles),
+            Some(WfcError::Contradiction { .. })
+        ));
+
+        // an id a tile mask has no room for
+        let mut far = rules.clone();
+        far.0.insert(TileID(256), AdjacencyRules::default());
+        assert_eq!(build(far, &tiles), Some(WfcError::TooManyTiles(257)));
+
+        tiles.0.get_mut(&TileID(2)).unwrap().weight = 0;
+        assert_eq!(
+            build(rules, &tiles),
+            Some(WfcError::MissingWeight(TileID(2)))
+        );
+    }
+
+    #[test]
+    fn test_steps_match_build() {
+        let (tiles, rules) = landscape();
+        let builder = || {
+            ChunkBuilder::new(ChunkId::default(), DIMS)
+                .with_seed(4)
+                .add_rule_set(rules.clone())
+        };
+
+        let mut stepper = builder();
+        let mut steps = 0;
+        loop {
+            match stepper.step(&tiles).unwrap() {
+                WfcStep::Collapsed { cell, tile, shrunk } => {
+                    assert!(shrunk.contains(&cell));
+                    assert_eq!(stepper.superposition(cell), TileMask::single(tile));
                 }
+                WfcStep::Finished => break,
+                _ => (),
             }
+            steps += 1;
         }
+        assert_eq!(stepper.step(&tiles), Ok(WfcStep::Finished));
+
+        // every step collapses a cell, the counts do not depend on how the builder is driven
+        let (chunk, stats) = builder().build_with_stats(&tiles).unwrap();
+        let stepped = stepper.stats().clone();
+        assert_eq!(stats.collapses, steps);
+        assert_eq!(
+            (stepped.collapses, stepped.removals, stepped.backtracks),
+            (stats.collapses, stats.removals, stats.backtracks)
+        );
+        assert!(stats.removals > 0);
+        assert!(stats.contradictions >= stats.restarts);
+        let phases = stats.init_time + stats.select_time + stats.collapse_time;
+        assert_eq!(stats.total_time(), phases + stats.backtrack_time);
+        assert_eq!(stepper.finish().tiles, chunk.tiles);
     }
 
-    fn shannon_entropy(&self, ids: &[TileID], tiles: &Tiles) -> Option<f32> {
-        if ids.len() == 0 {
-            return None;
-        }
-        let weights = ids
-            .iter()
-            .filter_map(|id| tiles.0.get(id).map(|tile| tile.weight as f32));
-        let sum: f32 = weights.clone().sum();
-        let sum2: f32 = weights.fold(0.0, |acc, weight| acc + weight * f32::log2(weight));
-        Some(sum - sum2 / sum)
-    }
-
-    // find superposition with lowest non zero entropy
-    fn lowest_entropy(&self, tiles: &Tiles) -> Option<usize> {
-        let mut pos = None;
-        let mut min = f32::MAX;
-        for (i, wave_state) in self.wave.iter().enumerate() {
-            let WaveState::Superpos(p) = wave_state else {
-                continue;
-            };
-            let Some(entropy) = self.shannon_entropy(p, tiles) else {
-                continue;
+    #[test]
+    fn test_cell_selectors() {
+        let (tiles, rules) = landscape();
+        let seed = UVec3::new(20, 2, 10);
+        for selector in [
+            CellSelector::MinRemainingValues,
+            CellSelector::Entropy,
+            CellSelector::Scanline,
+            CellSelector::Distance(seed),
+        ] {
+            let mut builder = ChunkBuilder::new(ChunkId::default(), DIMS)
+                .with_selector(selector)
+                .add_rule_set(rules.clone());
+            assert!(builder.init(&tiles).is_ok());
+            let open: Vec<usize> = (0..DIMS.volume())
+                .filter(|&pos| builder.wave[pos].len() > 1)
+                .collect();
+            let entropies: Vec<f32> = open
+                .iter()
+                .map(|&pos| builder.shannon_entropy(pos))
+                .collect();
+            let lens: Vec<usize> = builder.wave.iter().map(|mask| mask.len()).collect();
+            let seed_open = lens[DIMS.index(20, 2, 10)] > 1;
+            let first_step = builder.step(&tiles);
+            let Ok(WfcStep::Collapsed { cell: first, .. }) = first_step else {
+                panic!("{:?}: unexpected first step {:?}", selector, first_step);
             };
-            if entropy < min {
-                min = entropy;
-                pos = Some(i);
+            let first_pos = DIMS.index(first.x as usize, first.y as usize, first.z as usize);
+            match selector {
+                // ties go to the lower index
+                CellSelector::MinRemainingValues => {
+                    let fewest = open.iter().min_by_key(|&&pos| lens[pos]);
+                    assert_eq!(Some(&first_pos), fewest)
+                }
+                // the noise only breaks ties
+                CellSelector::Entropy => {
+                    let lowest = entropies.iter().cloned().fold(f32::MAX, f32::min);
+                    let index = open.iter().position(|&pos| pos == first_pos).unwrap();
+                    assert!(entropies[index] <= lowest + 1e-3, "{:?}", first)
+                }
+                CellSelector::Scanline => assert_eq!(Some(&first_pos), open.first()),
+                CellSelector::Distance(seed) if seed_open => assert_eq!(first, seed),
+                CellSelector::Distance(seed) => {
+                    let distance =
+                        |pos: usize| cell(pos).as_ivec3().distance_squared(seed.as_ivec3());
+                    let closest = open.iter().map(|&pos| distance(pos)).min();
+                    assert_eq!(Some(distance(first_pos)), closest)
+                }
             }
+            while builder.step(&tiles).unwrap() != WfcStep::Finished {}
+            assert!(builder.finish().tiles.iter().all(|tile| tile.is_some()));
         }
-        pos
     }
 
-    fn random_by_weight(&self, ids: &[TileID], tiles: &Tiles) -> Option<usize> {
-        let weights = ids
-            .iter()
-            .filter_map(|id| tiles.0.get(id).map(|tile| tile.weight));
-        let sum: usize = weights.clone().sum();
-        let mut rng = rand::thread_rng();
-        let random = rng.gen_range(0..sum);
-        let mut cursor = 0;
-        for (i, weight) in weights.enumerate() {
-            cursor += weight;
-            if cursor > random {
-                return Some(i);
+    #[test]
+    fn test_queue_matches_full_scan() {
+        let (tiles, rules) = landscape();
+        let mut builder = ChunkBuilder::new(ChunkId::default(), DIMS)
+            .with_seed(6)
+            .add_rule_set(rules);
+        assert!(builder.init(&tiles).is_ok());
+        let mut steps = 0;
+        while let Some(pos) = builder.select_cell() {
+            let scan = (0..DIMS.volume())
+                .filter(|&pos| builder.wave[pos].len() > 1)
+                .min_by_key(|&pos| builder.wave[pos].len());
+            assert_eq!(Some(pos), scan);
+            // the cached sums have to survive removals as well as undos, the landscape is solved
+            // without contradictions, so every tenth collapse is undone by hand
+            if steps % 10 == 0 {
+                if !builder.decisions.is_empty() {
+                    let backtracked = builder.backtrack(WfcError::EmptyRuleSet, &mut vec![]);
+                    assert!(backtracked.is_ok());
+                }
+                for pos in 0..DIMS.volume() {
+                    let weights = builder.wave[pos]
+                        .iter()
+                        .map(|id| fixed_weight(tiles.0[&id].weight as f64));
+                    assert_eq!(builder.weight_sums[pos], weights.clone().sum::<u64>());
+                    let log_sum: i64 = weights.map(weight_log).sum();
+                    assert_eq!(builder.weight_log_sums[pos], log_sum);
+                }
             }
+            builder.step(&tiles).unwrap();
+            steps += 1;
         }
-        None
     }
 
-    // collapse superposition in random element
-    fn collapse(&mut self, pos: usize, tiles: &Tiles) -> Result<TileID, WaveError> {
-        let WaveState::Superpos(ref superpos) = self.wave[pos] else {
-            return Err(WaveError("Cannot Collapse an already collapsed element."));
-        };
-        let Some(index) = self.random_by_weight(&superpos, tiles) else {
-            return Err(WaveError("No Element to collapse found!"));
+    // every pair of horizontally adjacent tiles in the world map is allowed by the rules
+    #[test]
+    fn test_neighbors_join_legally() {
+        let (tiles, rules) = landscape();
+        let mut world_map = WorldMap::default();
+        for (seed, (x, z)) in [(0, 0), (1, 0), (0, 1), (1, 1)].into_iter().enumerate() {
+            let chunk = ChunkBuilder::new(ChunkId::new(x, z), DIMS)
+                .with_seed(seed as u64)
+                .add_rule_set(rules.clone())
+                .with_neighbors(&world_map)
+                .build(&tiles)
+                .unwrap();
+            world_map.add_chunk(chunk);
+        }
+        assert_world_is_legal(&world_map, &rules);
+    }
+
+    #[test]
+    fn test_repair_in_blocks() {
+        let (tiles, rules) = landscape();
+        let mut world_map = WorldMap::default();
+        for (seed, (x, z)) in [(-1, 0), (1, 0), (0, -1), (0, 1)].into_iter().enumerate() {
+            let ctx = ChunkContext::new(&tiles, &rules).with_seed(WorldSeed(seed as u64));
+            generate_chunk(&mut world_map, &ChunkId::new(x, z), ctx).unwrap();
+        }
+        let before = world_map.get(&ChunkId::new(1, 0)).unwrap().tiles.clone();
+
+        let id = ChunkId::new(0, 0);
+        let ctx = ChunkContext::new(&tiles, &rules).with_seed(WorldSeed(7));
+        let changed = repair_chunk(&mut world_map, &id, ctx).unwrap();
+        assert_eq!(changed.len(), 5, "{:?}", changed);
+        let chunk = world_map.get(&id).unwrap();
+        assert!(chunk.tiles.iter().all(|tile| tile.is_some()));
+        // the half of the neighbor that is not covered by a block stays as it was
+        let after = &world_map.get(&ChunkId::new(1, 0)).unwrap().tiles;
+        for pos in 0..DIMS.volume() {
+            if DIMS.from_index(pos).0 >= DIMS.size() / 2 {
+                assert_eq!(before[pos], after[pos]);
+            }
+        }
+        assert_world_is_legal(&world_map, &rules);
+    }
+
+    #[test]
+    fn test_repair_keeps_the_pins() {
+        let (tiles, rules) = landscape();
+        let (id, right) = (ChunkId::new(0, 0), ChunkId::new(1, 0));
+        let pins = [
+            (id.clone(), UVec3::new(1, 0, 1), TileID(0)),
+            (id.clone(), UVec3::new(6, 3, 5), TileID(4)),
+            // the block at the corner covers the left half of the neighbor
+            (right.clone(), UVec3::new(2, 1, 3), TileID(1)),
+        ];
+        let mut constraints = WorldConstraints::default();
+        for (chunk, cell, tile) in pins.iter() {
+            let entry = constraints.chunks.entry(chunk.clone()).or_default();
+            entry.pins.push((*cell, TileMask::single(*tile)));
+        }
+        let ctx = ChunkContext::new(&tiles, &rules)
+            .with_seed(WorldSeed(5))
+            .with_constraints(&constraints);
+        let mut world_map = WorldMap::default();
+        for (x, z) in [(-1, 0), (1, 0), (0, -1), (0, 1)] {
+            generate_chunk(&mut world_map, &ChunkId::new(x, z), ctx).unwrap();
+        }
+        let pinned = |world_map: &WorldMap| {
+            pins.iter().all(|(chunk, cell, tile)| {
+                let (x, y, z) = (cell.x as usize, cell.y as usize, cell.z as usize);
+                world_map.get(chunk).unwrap().get_tile(x, y, z) == Some(*tile)
+            })
         };
-        let tile = superpos[index];
-        info!("WFC: Selected {:?} from Superpos {:?}", tile, superpos);
-        // superpos.clear();
-        self.wave[pos] = WaveState::Collapsed(tile);
-        Ok(tile)
-    }
-
-    // propagate the rules for a tile collapsed at pos
-    fn propagate(&mut self, pos: usize) {
-        let mut stack = Vec::new();
-        stack.push(pos);
-        while !stack.is_empty() {
-            let current_pos = stack.pop().unwrap();
-            for dir in Dir::iter() {
-                let Some(neighbor_pos) = self.neighbor(pos, dir) else {
-                    continue;
-                };
-                let WaveState::Superpos(neighbor) = self.wave[neighbor_pos].clone() else {
-                    continue;
-                };
-                let allowed_neighbors: Vec<TileID> = match &self.wave[current_pos] {
-                    WaveState::Collapsed(tile) => self.rules[tile].from_dir(dir).to_vec(),
-                    WaveState::Superpos(list) => {
-                        let mut res = Vec::new();
-                        for tile in list {
-                            res.append(&mut self.rules[tile].from_dir(dir).to_vec());
-                        }
-                        res
-                    }
+
+        // without the constraints the repair overwrites the pins
+        let mut unpinned = world_map.clone();
+        let free = ChunkContext::new(&tiles, &rules).with_seed(WorldSeed(5));
+        repair_chunk(&mut unpinned, &id, free).unwrap();
+        assert!(!pinned(&unpinned));
+
+        repair_chunk(&mut world_map, &id, ctx).unwrap();
+        assert!(pinned(&world_map));
+        assert_world_is_legal(&world_map, &rules);
+
+        // a pin above the top level is an error, also for the blocks of a repair
+        let above = UVec3::new(1, DIMS.height() as u32, 1);
+        let mut constraints = WorldConstraints::default();
+        constraints.chunks.insert(
+            id.clone(),
+            ChunkConstraints::default().pin(above, TileID(0)),
+        );
+        let ctx = ctx.with_constraints(&constraints);
+        let outside = Err(WfcError::PinOutside { x: 1, y: 4, z: 1 });
+        assert_eq!(repair_chunk(&mut world_map.clone(), &id, ctx), outside);
+        assert_eq!(generate_chunk(&mut WorldMap::default(), &id, ctx), outside);
+    }
+
+    #[test]
+    fn test_repair_keeps_the_counts() {
+        let (tiles, rules) = landscape();
+        let (id, right) = (ChunkId::new(0, 0), ChunkId::new(1, 0));
+        let half = UVec3::new(
+            DIMS.size() as u32 / 2,
+            DIMS.height() as u32,
+            DIMS.size() as u32,
+        );
+        let count = |prototype: &str| TileCount::new(prototype, &DIMS);
+        let mut constraints = WorldConstraints::default();
+        constraints.chunks.insert(
+            id.clone(),
+            ChunkConstraints::default()
+                .with_count(count("water").at_most(0))
+                .with_count(count("grass").at_least_share(0.3)),
+        );
+        // only the left half of the neighbor is covered by the blocks
+        constraints.chunks.insert(
+            right.clone(),
+            ChunkConstraints::default()
+                .with_count(count("rock").in_region(UVec3::ZERO, half).at_most(0))
+                .with_count(count("water").clusters().at_most(2)),
+        );
+        let ctx = ChunkContext::new(&tiles, &rules)
+            .with_seed(WorldSeed(5))
+            .with_constraints(&constraints);
+        let mut world_map = WorldMap::default();
+        for (x, z) in [(-1, 0), (1, 0), (0, -1), (0, 1)] {
+            generate_chunk(&mut world_map, &ChunkId::new(x, z), ctx).unwrap();
+        }
+        let kept = |world_map: &WorldMap| {
+            let mut loaded = constraints
+                .chunks
+                .iter()
+                .filter(|(id, _)| world_map.contains(id));
+            loaded.all(|(id, constraints)| {
+                let chunk = world_map.get(id).unwrap();
+                let in_range = |count: &TileCount| {
+                    (count.min..=count.max).contains(&count.counted(chunk, &tiles))
                 };
+                constraints.counts.iter().all(in_range)
+            })
+        };
+        assert!(kept(&world_map));
 
-                for tile in neighbor {
-                    if allowed_neighbors.contains(&tile) {
-                        continue;
-                    }
+        // without the constraints the repair breaks the counts
+        let mut unconstrained = world_map.clone();
+        let free = ChunkContext::new(&tiles, &rules).with_seed(WorldSeed(5));
+        repair_chunk(&mut unconstrained, &id, free).unwrap();
+        assert!(!kept(&unconstrained));
 
-                    // remove tile from neighbors
-                    if let WaveState::Superpos(n) = &mut self.wave[neighbor_pos] {
-                        let index = n.iter().position(|&i| i == tile).unwrap();
-                        n.remove(index);
-                    }
+        repair_chunk(&mut world_map, &id, ctx).unwrap();
+        assert!(kept(&world_map));
+        assert_world_is_legal(&world_map, &rules);
+    }
 
-                    if stack.contains(&neighbor_pos) {
-                        stack.push(neighbor_pos)
-                    }
-                }
-            }
+    #[test]
+    fn test_repair_task() {
+        let (tiles, rules) = landscape();
+        let ctx = ChunkContext::new(&tiles, &rules).with_seed(WorldSeed(7));
+        let mut world_map = WorldMap::default();
+        for (x, z) in [(-1, 0), (1, 0), (0, -1), (0, 1)] {
+            generate_chunk(&mut world_map, &ChunkId::new(x, z), ctx).unwrap();
+        }
+        let id = ChunkId::new(0, 0);
+        let mut expected = world_map.clone();
+        let changed = repair_chunk(&mut expected, &id, ctx).unwrap();
+
+        // the task repairs a copy, the same way repair_chunk does
+        let repair = futures_lite::future::block_on(repair_task(&world_map, &id, ctx)).unwrap();
+        assert!(!world_map.contains(&id));
+        let mut applied = world_map.clone();
+        assert_eq!(repair.apply(&mut applied), Some(changed.clone()));
+        assert!(changed.iter().all(|id| applied.get(id) == expected.get(id)));
+
+        // a chunk next to the repaired one was added in the meantime
+        let repair = futures_lite::future::block_on(repair_task(&world_map, &id, ctx)).unwrap();
+        let diagonal = ChunkId::new(1, 1);
+        generate_chunk(&mut world_map, &diagonal, ctx).unwrap();
+        let before = world_map.clone();
+        assert_eq!(repair.apply(&mut world_map), None);
+        for id in repair_area(&id) {
+            assert!(world_map.get(&id) == before.get(&id));
         }
+
+        let repair = futures_lite::future::block_on(repair_task(&world_map, &id, ctx)).unwrap();
+        assert!(repair.apply(&mut world_map).is_some());
+        assert_world_is_legal(&world_map, &rules);
     }
 
-    fn iterate(&mut self, tiles: &Tiles) -> Result<(), WaveError> {
-        // info!("WFC: INTERATE");
-        let Some(pos) = self.lowest_entropy(tiles) else {
-            return Err(WaveError("No element with lowest entropy found."));
+    #[test]
+    fn test_pinned_cells() {
+        let (tiles, rules) = landscape();
+        let plateau: Vec<UVec3> = (4..8)
+            .flat_map(|x| (4..8).map(move |z| UVec3::new(x, 0, z)))
+            .collect();
+        let wall = UVec3::new(0, 3, 0);
+        let wall_tiles: TileMask = [TileID(2), TileID(3)].into_iter().collect();
+        let builder = || {
+            let mut builder =
+                ChunkBuilder::new(ChunkId::default(), DIMS).add_rule_set(rules.clone());
+            for &cell in plateau.iter() {
+                builder = builder.pin(cell, TileID(0));
+            }
+            builder.pin_set(wall, wall_tiles)
         };
-        let tile = self.collapse(pos, tiles)?;
-        // info!("WFC: Collapse {:?} {:?}", from_index(pos), tile);
-        self.propagate(pos);
 
-        Ok(())
+        let mut pinned = builder();
+        assert!(pinned.init(&tiles).is_ok());
+        assert_eq!(
+            pinned.superposition(plateau[0]),
+            TileMask::single(TileID(0))
+        );
+        assert!(wall_tiles
+            .iter()
+            .any(|id| pinned.superposition(wall).contains(id)));
+
+        let chunk = builder().build(&tiles).unwrap();
+        for &cell in plateau.iter() {
+            let (x, y, z) = (cell.x as usize, cell.y as usize, cell.z as usize);
+            assert_eq!(chunk.get_tile(x, y, z), Some(TileID(0)));
+        }
+        assert!(wall_tiles.contains(chunk.get_tile(0, 3, 0).unwrap()));
+
+        // the ground is restricted to the lower levels
+        let result = builder().pin(wall, TileID(0)).build(&tiles);
+        assert_eq!(
+            result.err(),
+            Some(WfcError::Contradiction { x: 0, y: 3, z: 0 })
+        );
+        let result = builder().pin(UVec3::new(0, 4, 32), TileID(0)).build(&tiles);
+        assert_eq!(
+            result.err(),
+            Some(WfcError::PinOutside { x: 0, y: 4, z: 32 })
+        );
     }
 
-    fn neighbor(&self, pos: usize, dir: Dir) -> Option<usize> {
-        let (x, y, z) = from_index(pos);
-        let (x_off, y_off, z_off): (isize, isize, isize) = match dir {
-            Dir::Forward => (0, 0, -1),
-            Dir::Backward => (0, 0, 1),
-            Dir::Left => (-1, 0, 0),
-            Dir::Right => (1, 0, 0),
-            Dir::Up => (0, 1, 0),
-            Dir::Down => (0, -1, 0),
+    #[test]
+    fn test_tile_counts() {
+        let (tiles, rules) = landscape();
+        let region = (UVec3::new(0, 0, 0), UVec3::new(8, 2, 8));
+        let build = |count: TileCount| {
+            ChunkBuilder::new(ChunkId::default(), DIMS)
+                .with_seed(8)
+                .add_rule_set(rules.clone())
+                .with_count(count)
+                .build(&tiles)
         };
-        let x_res = if (x == 0 && x_off == -1) || (x == CHUNK_SIZE - 1 && x_off == 1) {
-            return None;
-        } else {
-            (x as isize + x_off) as usize
+        let count = |chunk: &Chunk, prototype: &str, region: (UVec3, UVec3)| {
+            let count = TileCount::new(prototype, &DIMS).in_region(region.0, region.1);
+            (0..DIMS.volume())
+                .filter(|&pos| count.contains(cell(pos)))
+                .filter(|&pos| tiles.0[&chunk.tiles[pos].unwrap()].prototype == prototype)
+                .count()
         };
 
-        let y_res = if (y == 0 && y_off == -1) || (y == CHUNK_HIGHT - 1 && y_off == 1) {
-            return None;
-        } else {
-            (y as isize + y_off) as usize
+        let chunk = build(TileCount::new("grass", &DIMS).at_most(0)).unwrap();
+        assert_eq!(count(&chunk, "grass", TileCount::new("", &DIMS).region), 0);
+
+        let limited = TileCount::new("sand", &DIMS)
+            .in_region(region.0, region.1)
+            .at_least_share(0.25)
+            .at_most(40);
+        assert_eq!(limited.min, 32);
+        let chunk = build(limited).unwrap();
+        assert!((32..=40).contains(&count(&chunk, "sand", region)));
+
+        // the ground can only be placed in the lower half of the chunk
+        let result = build(TileCount::new("ground", &DIMS).at_least_share(0.6));
+        assert_eq!(result.err(), Some(WfcError::TileCount("ground".into())));
+
+        // the limit counts cells, so a single patch of four cells exceeds a limit of three
+        let patch = |count: TileCount| {
+            let mut builder = ChunkBuilder::new(ChunkId::default(), DIMS)
+                .with_seed(8)
+                .add_rule_set(rules.clone())
+                .with_count(count);
+            for (x, z) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                builder = builder.pin(UVec3::new(x, 0, z), TileID(1));
+            }
+            builder.build(&tiles)
         };
+        let cells = TileCount::new("grass", &DIMS).at_most(3);
+        let result = patch(cells.clone());
+        assert_eq!(result.err(), Some(WfcError::TileCount("grass".into())));
 
-        let z_res = if (z == 0 && z_off == -1) || (z == CHUNK_SIZE - 1 && z_off == 1) {
-            return None;
-        } else {
-            (z as isize + z_off) as usize
+        // but it is a single cluster
+        let corner = (UVec3::ZERO, UVec3::new(8, 4, 8));
+        let clusters = cells.in_region(corner.0, corner.1).clusters();
+        let chunk = patch(clusters.clone()).unwrap();
+        assert!((1..=3).contains(&clusters.counted(&chunk, &tiles)));
+        let single = clusters.clone().at_most(1);
+        let chunk = patch(single.clone()).unwrap();
+        assert_eq!(single.counted(&chunk, &tiles), 1);
+        let cells = TileCount {
+            clusters: false,
+            ..single
         };
+        assert!(cells.counted(&chunk, &tiles) > 4);
+        let apart = clusters.at_least(3);
+        let chunk = patch(apart.clone()).unwrap();
+        assert_eq!(apart.counted(&chunk, &tiles), 3);
+    }
+
+    #[test]
+    fn test_walk_changed() {
+        let mask = |ids: &[u32]| ids.iter().map(|&id| TileID(id)).collect::<TileMask>();
+        // floors 0 and 1, a wall 2 and a ramp 3 leading up to the right
+        let mut builder = ChunkBuilder::new(ChunkId::default(), DIMS).with_reachability();
+        builder.walkable = mask(&[0, 1, 3]);
+        builder.ramps[Dir::Right as usize] = mask(&[3]);
 
-        Some(get_index(x_res, y_res, z_res))
+        // a walkable tile is left and the cell may still be a wall
+        assert!(!builder.walk_changed(mask(&[0, 1, 2]), mask(&[1, 2])));
+        // the last walkable tile
+        assert!(builder.walk_changed(mask(&[0, 2]), mask(&[2])));
+        // the cell has to be walkable now
+        assert!(builder.walk_changed(mask(&[0, 2]), mask(&[0])));
+        // the last ramp
+        assert!(builder.walk_changed(mask(&[0, 2, 3]), mask(&[0, 2])));
     }
 
-    fn is_collapsed(&self) -> bool {
-        for wave_state in &self.wave {
-            if !wave_state.is_collapsed() {
-                return false;
+    // floors on the two lower levels, walls everywhere and ramps leading up to the right
+    fn walk_tileset() -> (Tiles, AdjRuleSet) {
+        let mut tiles = HashMap::new();
+        let mut rules = HashMap::new();
+        let levels = [Some(0..1), Some(1..2), None, Some(0..1)];
+        for (id, level) in levels.clone().into_iter().enumerate() {
+            let id = TileID(id as u32);
+            let tile = Tile {
+                weight: if id == TileID(2) { 8 } else { 1 },
+                y_level: level,
+                walkable: id != TileID(2),
+                ramp: (id == TileID(3)).then_some(Dir::Right),
+                ..tile(id.0, &format!("prototype{}", id.0))
+            };
+            tiles.insert(id, tile);
+            let mut rule = AdjacencyRules::default();
+            for dir in Dir::iter() {
+                for other in 0..levels.len() as u32 {
+                    rule.insert(dir, TileID(other));
+                }
             }
+            rules.insert(id, rule);
         }
-        true
+        (Tiles(tiles), AdjRuleSet(rules))
     }
 
-    fn branch(&mut self, tiles: &Tiles) -> Result<(), WaveError> {
-        // info!("WFC: BRANCH");
-        if self.is_collapsed() {
-            return Ok(());
+    #[test]
+    fn test_walkable_cells_are_connected() {
+        let (tiles, rules) = walk_tileset();
+        let build = |reachability: bool| {
+            let mut builder = ChunkBuilder::new(ChunkId::default(), DIMS)
+                .with_seed(3)
+                .add_rule_set(rules.clone());
+            if reachability {
+                builder = builder.with_reachability();
+            }
+            while builder.step(&tiles).unwrap() != WfcStep::Finished {}
+            builder
+        };
+        let connected = |builder: &ChunkBuilder| {
+            let walkable: Vec<usize> = (0..DIMS.volume())
+                .filter(|&pos| builder.must_walk(pos))
+                .collect();
+            let mut reached = vec![false; DIMS.volume()];
+            let mut stack = vec![walkable[0]];
+            reached[walkable[0]] = true;
+            while let Some(pos) = stack.pop() {
+                for next in builder.walk_neighbors(pos) {
+                    if !reached[next] {
+                        reached[next] = true;
+                        stack.push(next);
+                    }
+                }
+            }
+            walkable.iter().all(|&pos| reached[pos])
+        };
+
+        assert!(!connected(&build(false)));
+        let builder = build(true);
+        assert!(connected(&builder));
+        for reachability in [false, true] {
+            let chunk = build(reachability).build(&tiles).unwrap();
+            assert_eq!(chunk.is_reachable(&tiles), reachability);
         }
+        // the upper level can only be reached over ramps
+        let upper =
+            (0..DIMS.volume()).filter(|&pos| builder.wave[pos] == TileMask::single(TileID(1)));
+        assert!(upper.count() > 0);
+    }
 
-        let Some(min_ent) = self
-            .wave
-            .iter()
-            .filter_map(|state| match state {
-                WaveState::Superpos(vec) => Some(vec.len()),
-                WaveState::Collapsed(_) => None,
-            })
-            .min()
-        else {
-            return Err(WaveError("No lowest_entropy found"));
+    #[test]
+    fn test_repair_keeps_the_chunks_reachable() {
+        let (tiles, rules) = walk_tileset();
+        let every_chunk = Some(ChunkConstraints::default().with_reachability());
+        let constraints = WorldConstraints {
+            every_chunk,
+            ..default()
         };
+        let ctx = ChunkContext::new(&tiles, &rules)
+            .with_seed(WorldSeed(2))
+            .with_constraints(&constraints);
+        let mut world_map = WorldMap::new(ChunkDims::new(12, 3));
+        for (x, z) in [(-1, 0), (1, 0), (0, -1), (0, 1)] {
+            generate_chunk(&mut world_map, &ChunkId::new(x, z), ctx).unwrap();
+        }
 
-        if min_ent == 0 {
-            return Err(WaveError("Lowest entropy cannot be zero!"));
+        // the blocks of the repair cut the chunk into four parts, which are connected again
+        let changed = repair_chunk(&mut world_map, &ChunkId::default(), ctx).unwrap();
+        assert!(changed.contains(&ChunkId::default()));
+        for id in changed.iter() {
+            assert!(world_map.get(id).unwrap().is_reachable(&tiles), "{:?}", id);
         }
+        assert_world_is_legal(&world_map, &rules);
+    }
 
-        let positions: Vec<usize> = self
-            .wave
-            .iter()
-            .enumerate()
-            .filter_map(|(i, state)| match state {
-                WaveState::Superpos(vec) if vec.len() == min_ent => Some(i),
-                _ => None,
-            })
-            .collect();
+    #[test]
+    fn test_surface_follows_height_field() {
+        let (mut tiles, rules) = landscape();
+        tiles.0.get_mut(&TileID(1)).unwrap().walkable = true;
+        let height_field = HeightField::new(1).with_scale(8.0).with_tolerance(1);
+        let id = ChunkId::new(-1, 2);
+        let chunk = ChunkBuilder::new(id.clone(), DIMS)
+            .add_rule_set(rules)
+            .with_height_field(height_field.clone())
+            .build(&tiles)
+            .unwrap();
 
-        for pos in positions {
-            let wave = self.wave.clone();
-            self.collapse(pos, tiles)?;
-            self.propagate(pos);
-            if let Err(_) = self.branch(tiles) {
-                self.wave = wave.clone();
-                continue;
+        let origin = id.origin(&DIMS);
+        let mut surface = 0;
+        for pos in 0..DIMS.volume() {
+            let (x, y, z) = DIMS.from_index(pos);
+            if chunk.tiles[pos] == Some(TileID(1)) {
+                let (x, z) = (origin.x + x as i32, origin.y + z as i32);
+                assert!(height_field.allows(x, y, z));
+                surface += 1;
             }
-            if self.is_collapsed() {
-                return Ok(());
+        }
+        assert!(surface > 0);
+    }
+
+    #[test]
+    fn test_weight_field_is_queried_per_cell() {
+        // tile 1 only in the left half of the world, tile 2 never
+        struct Halves;
+        impl WeightField for Halves {
+            fn weight(&self, pos: IVec3, tile: &Tile) -> f32 {
+                match tile.id {
+                    TileID(1) if pos.x >= 0 => 0.0,
+                    TileID(2) => 0.0,
+                    _ => tile.weight as f32,
+                }
             }
-            self.wave = wave.clone();
         }
+        // every tile connects to every other one, so only the weights decide
+        let pairs: Vec<(u32, u32)> = (0..3).flat_map(|a| (0..3).map(move |b| (a, b))).collect();
+        let (tiles, rules) = tileset(
+            vec![tile(0, "grass"), tile(1, "flower"), tile(2, "stone")],
+            &pairs,
+            &pairs,
+        );
+        let id = ChunkId::new(0, 0).x_offset(-1);
+        let mut builder = ChunkBuilder::block(
+            id.origin(&DIMS) + IVec2::new(DIMS.size() as i32 / 2, 0),
+            DIMS,
+        )
+        .add_rule_set(rules)
+        .with_weight_field(Arc::new(Halves));
+        while builder.step(&tiles).unwrap() != WfcStep::Finished {}
+
+        let placed = |tile: TileID| {
+            (0..DIMS.volume())
+                .filter(|&pos| builder.wave[pos] == TileMask::single(tile))
+                .map(|pos| builder.world_pos(pos))
+                .collect::<Vec<_>>()
+        };
+        assert!(placed(TileID(2)).is_empty());
+        assert!(!placed(TileID(1)).is_empty());
+        assert!(placed(TileID(1)).iter().all(|pos| pos.x < 0));
+    }
+
+    #[test]
+    fn test_fits_neighbors() {
+        let (tiles, rules) = landscape();
+        let mut world_map = WorldMap::default();
+        let left = ChunkId::new(0, 0);
+        let ctx = ChunkContext::new(&tiles, &rules);
+        generate_chunk(&mut world_map, &left, ctx).unwrap();
+        let right = ChunkId::new(1, 0);
+        let chunk = chunk_builder(&world_map, &right, ctx.with_seed(WorldSeed(1)))
+            .unwrap()
+            .build(&tiles)
+            .unwrap();
+        assert!(fits_neighbors(&chunk, &world_map, &rules));
+
+        // the neighbor changes after the chunk was built
+        let (_, y, z) = DIMS.from_index(
+            (0..DIMS.volume())
+                .find(|&pos| DIMS.from_index(pos).0 == 0)
+                .unwrap(),
+        );
+        let tile = chunk.get_tile(0, y, z).unwrap();
+        let misfit = (0..5)
+            .map(TileID)
+            .find(|&other| !allowed(&rules, tile, other, Dir::Left))
+            .unwrap();
+        let edge = IVec3::new(DIMS.size() as i32 - 1, y as i32, z as i32);
+        world_map.set_world_tile(edge, Some(misfit));
+        assert!(!fits_neighbors(&chunk, &world_map, &rules));
+    }
+
+    #[test]
+    fn test_generate_chunks_in_parallel() {
+        let (tiles, rules) = landscape();
+        let ids: Vec<ChunkId> = (-1..=1)
+            .flat_map(|x| (-1..=1).map(move |z| ChunkId::new(x, z)))
+            .collect();
+        assert_eq!(chunk_color(&ids[0]), chunk_color(&ids[2]));
+        assert_ne!(chunk_color(&ids[0]), chunk_color(&ids[1]));
+
+        let mut world_map = WorldMap::default();
+        let ctx = ChunkContext::new(&tiles, &rules).with_seed(WorldSeed(4));
+        let changed = generate_chunks(&mut world_map, &ids, ctx).unwrap();
+        assert_eq!(changed.len(), ids.len());
+        assert!(ids.iter().all(|id| world_map.contains(id)));
+        assert_world_is_legal(&world_map, &rules);
+
+        // the result does not depend on the order the threads finish in
+        let mut again = WorldMap::default();
+        generate_chunks(&mut again, &ids, ctx).unwrap();
+        for id in ids.iter() {
+            assert!(world_map.get(id).unwrap().tiles == again.get(id).unwrap().tiles);
+        }
+    }
+
+    #[test]
+    fn test_chunk_dims() {
+        let (tiles, rules) = landscape();
+        let dims = ChunkDims::new(6, 10);
+        let mut world_map = WorldMap::new(dims);
+        let ids = [ChunkId::new(0, 0), ChunkId::new(1, 0), ChunkId::new(0, -1)];
+        let ctx = ChunkContext::new(&tiles, &rules).with_seed(WorldSeed(1));
+        generate_chunks(&mut world_map, &ids, ctx).unwrap();
+        assert_world_is_legal(&world_map, &rules);
 
-        Err(WaveError("No solution found"))
+        let chunk = world_map.get(&ids[2]).unwrap();
+        assert_eq!(chunk.dims(), dims);
+        assert_eq!(chunk.tiles.len(), 6 * 6 * 10);
+        // the upper levels are filled as well
+        assert!(chunk.get_tile(5, 9, 5).is_some());
+        let tile = world_map.get_world_tile(IVec3::new(5, 9, -1));
+        assert_eq!(tile, chunk.get_tile(5, 9, 5));
+        assert_eq!(world_map.get_world_tile(IVec3::new(5, 10, -1)), None);
+        assert_eq!(
+            ChunkId::from_position(Vec3::new(6.5, 0.0, -0.5), &dims),
+            ChunkId::new(1, -1)
+        );
     }
 }
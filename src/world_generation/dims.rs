@@ -0,0 +1,107 @@
+use bevy::prelude::*;
+
+// Footprint and height of the chunks, in tiles. Every chunk of a world map has the same dimensions.
+// The fields are only set by new, so a chunk always has at least one cell.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChunkDims {
+    // number of tiles along x and z
+    size: usize,
+    // number of levels
+    height: usize,
+    // edge length of a tile in world units
+    tile_size: f32,
+}
+
+impl Default for ChunkDims {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl ChunkDims {
+    pub const DEFAULT: Self = Self {
+        size: 32,
+        height: 4,
+        tile_size: 1.0,
+    };
+
+    pub fn new(size: usize, height: usize) -> Self {
+        assert!(size > 0 && height > 0, "chunks need at least one cell");
+        Self {
+            size,
+            height,
+            ..default()
+        }
+    }
+
+    pub fn with_tile_size(mut self, tile_size: f32) -> Self {
+        self.tile_size = tile_size;
+        self
+    }
+
+    pub const fn size(&self) -> usize {
+        self.size
+    }
+
+    pub const fn height(&self) -> usize {
+        self.height
+    }
+
+    pub const fn tile_size(&self) -> f32 {
+        self.tile_size
+    }
+
+    pub fn area(&self) -> usize {
+        self.size * self.size
+    }
+
+    pub fn volume(&self) -> usize {
+        self.area() * self.height
+    }
+
+    // size of a chunk in world units
+    pub fn extent(&self) -> f32 {
+        self.size as f32 * self.tile_size
+    }
+
+    pub fn contains(&self, x: usize, y: usize, z: usize) -> bool {
+        x < self.size && y < self.height && z < self.size
+    }
+
+    #[inline]
+    pub fn index(&self, x: usize, y: usize, z: usize) -> usize {
+        y * self.area() + x * self.size + z
+    }
+
+    #[inline]
+    pub fn from_index(&self, index: usize) -> (usize, usize, usize) {
+        let z = index % self.size;
+        let x = index / self.size % self.size;
+        let y = index / self.area();
+
+        (x, y, z)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_index() {
+        for dims in [ChunkDims::default(), ChunkDims::new(5, 9)] {
+            for z in 0..dims.size {
+                for x in 0..dims.size {
+                    for y in 0..dims.height {
+                        let index = dims.index(x, y, z);
+                        assert!(index < dims.volume());
+                        let (x0, y0, z0) = dims.from_index(index);
+                        assert_eq!(x, x0, "compared x={x} and x0={x0}, for index={index}");
+                        assert_eq!(y, y0, "compared y={y} and y0={y0}, for index={index}");
+                        assert_eq!(z, z0, "compared z={z} and z0={z0}, for index={index}");
+                    }
+                }
+            }
+        }
+    }
+}
@@ -2,9 +2,11 @@ use std::f32::consts::PI;
 
 use bevy::math::Vec3;
 use bevy::math::Quat;
+use serde::{Deserialize, Serialize};
+use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
 
-#[derive(EnumIter, Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(EnumIter, Debug, PartialEq, Eq, Hash, Clone, Copy, Serialize, Deserialize)]
 pub enum Dir {
     Forward,  //-Z
     Backward, //Z
@@ -40,28 +42,28 @@ impl Dir {
     pub fn rotate_y(&self, rotation: Rotation) -> Self {
         match (self, rotation) {
             (Dir::Forward, Rotation::Zero) => Dir::Forward,
-            (Dir::Forward, Rotation::Quarter) => Dir::Left,
+            (Dir::Forward, Rotation::Quarter) => Dir::Right,
             (Dir::Forward, Rotation::Half) => Dir::Backward,
-            (Dir::Forward, Rotation::ThreeQuarter) => Dir::Right,
+            (Dir::Forward, Rotation::ThreeQuarter) => Dir::Left,
             (Dir::Backward, Rotation::Zero) => Dir::Backward,
-            (Dir::Backward, Rotation::Quarter) => Dir::Right,
+            (Dir::Backward, Rotation::Quarter) => Dir::Left,
             (Dir::Backward, Rotation::Half) => Dir::Forward,
-            (Dir::Backward, Rotation::ThreeQuarter) => Dir::Left,
+            (Dir::Backward, Rotation::ThreeQuarter) => Dir::Right,
             (Dir::Left, Rotation::Zero) => Dir::Left,
-            (Dir::Left, Rotation::Quarter) => Dir::Backward,
+            (Dir::Left, Rotation::Quarter) => Dir::Forward,
             (Dir::Left, Rotation::Half) => Dir::Right,
-            (Dir::Left, Rotation::ThreeQuarter) => Dir::Forward,
+            (Dir::Left, Rotation::ThreeQuarter) => Dir::Backward,
             (Dir::Right, Rotation::Zero) => Dir::Right,
-            (Dir::Right, Rotation::Quarter) => Dir::Forward,
+            (Dir::Right, Rotation::Quarter) => Dir::Backward,
             (Dir::Right, Rotation::Half) => Dir::Left,
-            (Dir::Right, Rotation::ThreeQuarter) => Dir::Backward,
+            (Dir::Right, Rotation::ThreeQuarter) => Dir::Forward,
             (Dir::Up, _) => Dir::Up,
             (Dir::Down, _) => Dir::Down,
         }
     }
 }
 
-#[derive(EnumIter,Hash, Eq, PartialEq, Clone, Copy, Debug)]
+#[derive(EnumIter,Hash, Eq, PartialEq, PartialOrd, Ord, Clone, Copy, Debug, Serialize, Deserialize)]
 pub enum Rotation {
     Zero,
     Quarter,
@@ -70,6 +72,21 @@ pub enum Rotation {
 }
 
 impl Rotation {
+    pub fn inverse(&self) -> Self {
+        match self {
+            Rotation::Zero => Rotation::Zero,
+            Rotation::Quarter => Rotation::ThreeQuarter,
+            Rotation::Half => Rotation::Half,
+            Rotation::ThreeQuarter => Rotation::Quarter,
+        }
+    }
+
+    // first this rotation, then the other one
+    pub fn combine(&self, other: Rotation) -> Self {
+        let quarters = (*self as usize + other as usize) % 4;
+        Rotation::iter().nth(quarters).expect("there are four rotations")
+    }
+
     pub fn to_quat(&self) -> Quat {
         match self {
             Rotation::Zero => Quat::from_rotation_y(0.0),
@@ -86,6 +103,33 @@ mod direction_test {
 
     use super::{Dir, Rotation};
 
+    // the table rotate_y had before it agreed with to_quat, append_rule rotated by the rotation
+    // itself back then
+    fn old_rotate_y(dir: Dir, rotation: Rotation) -> Dir {
+        let quarters = match rotation {
+            Rotation::Zero => 0,
+            Rotation::Quarter => 1,
+            Rotation::Half => 2,
+            Rotation::ThreeQuarter => 3,
+        };
+        // counter clockwise seen from above
+        let ring = [Dir::Forward, Dir::Left, Dir::Backward, Dir::Right];
+        match ring.iter().position(|&d| d == dir) {
+            Some(i) => ring[(i + quarters) % 4],
+            None => dir,
+        }
+    }
+
+    #[test]
+    fn test_inverse_rotation_keeps_the_rules() {
+        // append_rule now rotates by the inverse, which maps every socket like before
+        for dir in Dir::iter() {
+            for rot in Rotation::iter() {
+                assert_eq!(dir.rotate_y(rot.inverse()), old_rotate_y(dir, rot));
+            }
+        }
+    }
+
     #[test]
     fn test_rotation() {
         for dir in Dir::iter() {
@@ -96,4 +140,16 @@ mod direction_test {
             }
         }
     }
+
+    #[test]
+    fn test_combine() {
+        for dir in Dir::iter() {
+            for first in Rotation::iter() {
+                for second in Rotation::iter() {
+                    let combined = dir.rotate_y(first.combine(second));
+                    assert_eq!(dir.rotate_y(first).rotate_y(second), combined);
+                }
+            }
+        }
+    }
 }
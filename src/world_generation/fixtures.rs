@@ -0,0 +1,141 @@
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+use super::dir::{Dir, Rotation};
+use super::{AdjRuleSet, AdjacencyRules, Prototype, Socket, Tile, TileID, Tiles, WorldMap};
+
+// A tile of weight one that fits on every level, the tests change the fields they need.
+pub fn tile(id: u32, prototype: &str) -> Tile {
+    Tile {
+        id: TileID(id),
+        prototype: prototype.to_string(),
+        asset_handle: None,
+        weight: 1,
+        y_rotation: Rotation::Zero,
+        y_level: None,
+        walkable: false,
+        ramp: None,
+    }
+}
+
+// A prototype with the same socket on every side that stands on the ground.
+pub fn prototype(name: &'static str, side: Socket, top: Socket) -> Prototype {
+    Prototype {
+        name,
+        asset_handle: None,
+        p_x: side,
+        n_x: side,
+        p_y: top,
+        n_y: Socket::Ground,
+        p_z: side,
+        n_z: side,
+        weight: 1,
+        y_rotations: vec![Rotation::Zero],
+        y_level: None,
+        walkable: false,
+        ramp: None,
+    }
+}
+
+// The tiles with the given pairs as rules, side pairs may be next to each other on a level and
+// the second tile of an up pair may stand on the first one. The pairs connect both ways.
+pub fn tileset(tiles: Vec<Tile>, side: &[(u32, u32)], up: &[(u32, u32)]) -> (Tiles, AdjRuleSet) {
+    let mut rules: HashMap<TileID, AdjacencyRules> = HashMap::new();
+    let mut connect = |a: u32, b: u32, dir: Dir| {
+        rules.entry(TileID(a)).or_default().insert(dir, TileID(b));
+        let back = rules.entry(TileID(b)).or_default();
+        back.insert(dir.opposite(), TileID(a));
+    };
+    for &(a, b) in side {
+        for dir in [Dir::Forward, Dir::Backward, Dir::Left, Dir::Right] {
+            connect(a, b, dir);
+        }
+    }
+    for &(a, b) in up {
+        connect(a, b, Dir::Up);
+    }
+    let tiles = tiles.into_iter().map(|tile| (tile.id, tile)).collect();
+    (Tiles(tiles), AdjRuleSet(rules))
+}
+
+// Five tiles that only fit next to some of the others, so the solver has to propagate and can run
+// into contradictions. The ground only fits on the lowest two levels, water only borders sand.
+pub fn landscape() -> (Tiles, AdjRuleSet) {
+    let tiles = vec![
+        Tile {
+            weight: 3,
+            y_level: Some(0..2),
+            ..tile(0, "ground")
+        },
+        Tile {
+            weight: 2,
+            ..tile(1, "grass")
+        },
+        tile(2, "sand"),
+        Tile {
+            weight: 2,
+            ..tile(3, "water")
+        },
+        tile(4, "rock"),
+    ];
+    let side = [
+        (0, 0),
+        (0, 1),
+        (0, 4),
+        (1, 1),
+        (1, 2),
+        (1, 4),
+        (2, 2),
+        (2, 3),
+        (3, 3),
+        (4, 4),
+    ];
+    let up = [
+        (0, 0),
+        (0, 1),
+        (0, 2),
+        (0, 3),
+        (0, 4),
+        (1, 1),
+        (1, 2),
+        (1, 4),
+        (2, 2),
+        (2, 3),
+        (3, 2),
+        (3, 3),
+        (4, 1),
+        (4, 4),
+    ];
+    tileset(tiles, &side, &up)
+}
+
+// if both tiles allow the other one next to them in the direction
+pub fn allowed(rules: &AdjRuleSet, tile: TileID, other: TileID, dir: Dir) -> bool {
+    rules.0[&tile].from_dir(dir).contains(&other)
+        && rules.0[&other].from_dir(dir.opposite()).contains(&tile)
+}
+
+// every cell of the loaded chunks is solved and joins legally with the cells next to it
+pub fn assert_world_is_legal(world_map: &WorldMap, rules: &AdjRuleSet) {
+    for chunk in world_map.chunks.values() {
+        let dims = chunk.dims();
+        let origin = chunk.id().origin(&dims);
+        for pos in 0..dims.volume() {
+            let (x, y, z) = dims.from_index(pos);
+            let world_pos = IVec3::new(origin.x + x as i32, y as i32, origin.y + z as i32);
+            let tile = world_map.get_world_tile(world_pos).unwrap();
+            for dir in [Dir::Forward, Dir::Backward, Dir::Left, Dir::Right] {
+                let outside = world_pos + dir.to_vec3().as_ivec3();
+                let Some(other) = world_map.get_world_tile(outside) else {
+                    continue;
+                };
+                assert!(
+                    allowed(rules, tile, other, dir),
+                    "{:?} {:?}",
+                    world_pos,
+                    dir
+                );
+            }
+        }
+    }
+}
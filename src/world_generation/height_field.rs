@@ -0,0 +1,125 @@
+use bevy::prelude::*;
+
+use super::util::mix_seed;
+use super::ChunkDims;
+
+// Fractal value noise over the world tile coordinates, it decides on which level the walkable
+// surface tiles lie. Sampling in world coordinates keeps the hills coherent across chunks.
+#[derive(Resource, Debug, Clone, PartialEq)]
+pub struct HeightField {
+    pub seed: u64,
+    // size of the largest hills in tiles
+    pub scale: f32,
+    // every octave adds details at half the scale and half the amplitude
+    pub octaves: u32,
+    // the surface may lie this many levels above or below the sampled level
+    pub tolerance: usize,
+    // number of levels, the height of the chunks
+    pub height: usize,
+}
+
+impl HeightField {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            scale: 24.0,
+            octaves: 3,
+            tolerance: 0,
+            height: ChunkDims::DEFAULT.height(),
+        }
+    }
+
+    pub fn with_scale(mut self, scale: f32) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    pub fn with_octaves(mut self, octaves: u32) -> Self {
+        self.octaves = octaves;
+        self
+    }
+
+    pub fn with_tolerance(mut self, tolerance: usize) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    pub fn with_height(mut self, height: usize) -> Self {
+        self.height = height;
+        self
+    }
+
+    // height at the world tile coordinates, between 0 and 1
+    pub fn sample(&self, x: i32, z: i32) -> f32 {
+        let mut sum = 0.0;
+        let mut amplitude = 1.0;
+        let mut total = 0.0;
+        let mut scale = self.scale;
+        for octave in 0..self.octaves.max(1) {
+            let seed = mix_seed(self.seed, octave as u64);
+            sum += amplitude * value_noise(seed, x as f32 / scale, z as f32 / scale);
+            total += amplitude;
+            amplitude *= 0.5;
+            scale = (scale * 0.5).max(1.0);
+        }
+        sum / total
+    }
+
+    // level of the surface at the world tile coordinates
+    pub fn level(&self, x: i32, z: i32) -> usize {
+        let level = (self.sample(x, z) * self.height as f32) as usize;
+        level.min(self.height.saturating_sub(1))
+    }
+
+    // if a surface tile may be placed at the world tile coordinates
+    pub fn allows(&self, x: i32, y: usize, z: i32) -> bool {
+        self.level(x, z).abs_diff(y) <= self.tolerance
+    }
+}
+
+// smoothly interpolated random values on the integer lattice, between 0 and 1
+pub fn value_noise(seed: u64, x: f32, z: f32) -> f32 {
+    let (x0, z0) = (x.floor(), z.floor());
+    let smooth = |t: f32| t * t * (3.0 - 2.0 * t);
+    let (tx, tz) = (smooth(x - x0), smooth(z - z0));
+    let (x0, z0) = (x0 as i32, z0 as i32);
+
+    let a = lattice(seed, x0, z0);
+    let b = lattice(seed, x0 + 1, z0);
+    let c = lattice(seed, x0, z0 + 1);
+    let d = lattice(seed, x0 + 1, z0 + 1);
+    let top = a + (b - a) * tx;
+    let bottom = c + (d - c) * tx;
+    top + (bottom - top) * tz
+}
+
+fn lattice(seed: u64, x: i32, z: i32) -> f32 {
+    let hash = mix_seed(mix_seed(seed, x as u32 as u64), z as u32 as u64);
+    // the upper 24 bits fit exactly into the mantissa
+    (hash >> 40) as f32 / (1u64 << 24) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_height_field_is_coherent() {
+        let field = HeightField::new(7);
+        assert_eq!(field.sample(-40, 13), HeightField::new(7).sample(-40, 13));
+        assert_ne!(field.sample(-40, 13), HeightField::new(8).sample(-40, 13));
+
+        let mut levels = vec![];
+        for x in -64..64 {
+            for z in -64..64 {
+                let height = field.sample(x, z);
+                assert!((0.0..1.0).contains(&height));
+                // neighboring tiles only differ slightly
+                assert!((height - field.sample(x + 1, z)).abs() < 0.2);
+                levels.push(field.level(x, z));
+            }
+        }
+        // but the world is not flat
+        assert!(levels.iter().any(|&level| level != levels[0]));
+    }
+}
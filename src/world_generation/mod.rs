@@ -1,31 +1,61 @@
+use bevy::asset::LoadState;
 use bevy::gltf::Gltf;
 use bevy::prelude::*;
+use bevy::tasks::{AsyncComputeTaskPool, Task, TaskPool};
 use bevy::utils::hashbrown::HashMap;
+use futures_lite::future;
 use std::f32::consts::PI;
 
 pub mod chunk;
+pub mod dims;
 pub mod dir;
+#[cfg(test)]
+mod fixtures;
+pub mod height_field;
+pub mod overlapping;
 pub mod prototype;
+pub mod rule_file;
+pub mod rule_learner;
 pub mod tile;
+pub mod tile_mask;
 pub mod util;
+pub mod validation;
+pub mod weight_field;
 
 use chunk::*;
+use dims::*;
+use height_field::*;
 use prototype::*;
 use tile::*;
+use tile_mask::*;
+use weight_field::*;
 
-pub const CHUNK_SIZE: usize = 32;
-pub const CHUNK_AREA: usize = CHUNK_SIZE * CHUNK_SIZE;
-pub const CHUNK_HIGHT: usize = 4;
-pub const CHUNK_VOLUME: usize = CHUNK_AREA * CHUNK_HIGHT;
 pub const CHUNK_SPAWN_DISTANCE: i32 = 1;
-pub const TILE_SIZE: f32 = 1.0;
+// how often a chunk is generated before it is given up, every attempt uses another seed
+pub const CHUNK_ATTEMPTS: u32 = 4;
+
+#[derive(Default)]
+pub struct WorldGenerationPlugin {
+    pub dims: ChunkDims,
+    // load the tiles and rules from a .ron or .json file instead of generating them
+    pub rule_file: Option<String>,
+    // every walkable cell of a chunk can be reached from the others in the same chunk
+    pub reachability: bool,
+}
 
-pub struct WorldGenerationPlugin;
 impl Plugin for WorldGenerationPlugin {
     fn build(&self, app: &mut App) {
         use PrototypesLoadState as PLS;
         app.insert_resource(WorldFocusPoint { pos: Vec3::ZERO })
-            .init_resource::<WorldMap>()
+            .init_resource::<WorldSeed>()
+            .insert_resource(WorldMap::new(self.dims))
+            .init_resource::<ChunkScheduler>()
+            .insert_resource(WorldConstraints {
+                every_chunk: self
+                    .reachability
+                    .then(|| ChunkConstraints::default().with_reachability()),
+                ..default()
+            })
             .add_state::<PLS>()
             .add_systems(OnEnter(PLS::Loading), load_prototypes)
             .add_systems(
@@ -34,23 +64,41 @@ impl Plugin for WorldGenerationPlugin {
             )
             .insert_resource(Tiles(HashMap::new()))
             .insert_resource(AdjRuleSet(HashMap::new()))
-            .add_systems(OnEnter(PLS::Finished), generate_tiles_and_rules)
-            .add_systems(Update, spawn_chunks.run_if(in_state(PLS::Finished)))
+            .add_systems(
+                Update,
+                (queue_chunks, spawn_chunks).run_if(in_state(PLS::Finished)),
+            )
             // .add_systems(Update, world_gizmo)
             // .add_systems(Update, grid_gizmo);
         ;
+        match &self.rule_file {
+            Some(path) => app.add_systems(
+                OnEnter(PLS::Finished),
+                (
+                    rule_file::import_rule_set(path.clone()),
+                    validation::report_imported_rule_issues,
+                )
+                    .chain(),
+            ),
+            None => app.add_systems(
+                OnEnter(PLS::Finished),
+                (generate_tiles_and_rules, validation::report_rule_issues).chain(),
+            ),
+        };
     }
 }
 
+#[allow(dead_code)]
 fn world_gizmo(mut gizmos: Gizmos, world_map: Res<WorldMap>) {
+    let dims = world_map.dims();
     for (_, chunk) in world_map.chunks.iter() {
         // draw tiles
-        for z in 0..CHUNK_SIZE {
-            for x in 0..CHUNK_SIZE {
+        for z in 0..dims.size() {
+            for x in 0..dims.size() {
                 gizmos.rect(
-                    chunk.pos() + Vec3::new(x as f32, 0.0, z as f32),
+                    chunk.pos() + Vec3::new(x as f32, 0.0, z as f32) * dims.tile_size(),
                     Quat::from_rotation_x(PI / 2.0),
-                    Vec2::splat(TILE_SIZE as f32),
+                    Vec2::splat(dims.tile_size()),
                     Color::YELLOW,
                 )
             }
@@ -59,13 +107,14 @@ fn world_gizmo(mut gizmos: Gizmos, world_map: Res<WorldMap>) {
     }
 }
 
-pub fn grid_gizmo(mut gizmos: Gizmos) {
+pub fn grid_gizmo(mut gizmos: Gizmos, world_map: Res<WorldMap>) {
+    let tile_size = world_map.dims().tile_size();
     for z in -50..50 {
         for x in -50..50 {
             gizmos.rect(
-                Vec3::new(x as f32, 0.0, z as f32),
+                Vec3::new(x as f32, 0.0, z as f32) * tile_size,
                 Quat::from_rotation_x(PI / 2.0),
-                Vec2::splat(TILE_SIZE as f32),
+                Vec2::splat(tile_size),
                 Color::YELLOW,
             )
         }
@@ -78,61 +127,396 @@ pub fn grid_gizmo(mut gizmos: Gizmos) {
     gizmos.ray(Vec3::ZERO, Vec3::Z, Color::GREEN);
 }
 
+// the chunk a task generated and what the solver did for it
+type BuildResult = Result<(Chunk, WfcStats), WfcError>;
+
+// Chunks that wait for their generation and the chunks that are generated in the background.
+// Chunks that share a border can not be generated at the same time, so only the chunks of one
+// color of the checkerboard run at once, the next color starts when all of them are finished.
+#[derive(Resource, Default)]
+pub struct ChunkScheduler {
+    queued: Vec<ChunkId>,
+    tasks: HashMap<ChunkId, Task<BuildResult>>,
+    // chunks that could not be solved next to their neighbors, the repairs count as tasks of the
+    // class that is running
+    repairs: HashMap<ChunkId, Task<Result<ChunkRepair, WfcError>>>,
+    // the results of the finished tasks, they wait for the rest of the class
+    built: Vec<(ChunkId, BuildResult)>,
+    repaired: Vec<(ChunkId, Result<ChunkRepair, WfcError>)>,
+    // the attempts of the chunks that had to be generated again
+    attempts: HashMap<ChunkId, u32>,
+    // the tile entities of every chunk, to despawn them when a repair changes the chunk
+    spawned: HashMap<ChunkId, Vec<Entity>>,
+    // changed chunks that wait for the models of their tiles to load before they are spawned
+    unspawned: Vec<ChunkId>,
+}
+
+// What finishing the tasks of the scheduler changed in the world map.
+#[derive(Default)]
+pub struct ChunkUpdate {
+    // chunks that were added, or changed by a repair
+    pub changed: Vec<ChunkId>,
+    // chunks that could not be generated in CHUNK_ATTEMPTS attempts, they are added empty so they
+    // are not retried
+    pub failed: Vec<(ChunkId, WfcError)>,
+    // what the solver did for every chunk that was built, including the ones that are retried
+    pub stats: Vec<(ChunkId, WfcStats)>,
+}
+
+impl ChunkScheduler {
+    pub fn queue(&mut self, id: ChunkId) {
+        if !self.is_scheduled(&id) {
+            self.queued.push(id);
+        }
+    }
+
+    pub fn is_scheduled(&self, id: &ChunkId) -> bool {
+        self.queued.contains(id)
+            || self.tasks.contains_key(id)
+            || self.repairs.contains_key(id)
+            || self.built.iter().any(|(built, _)| built == id)
+            || self.repaired.iter().any(|(repaired, _)| repaired == id)
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.queued.is_empty() && self.tasks.is_empty() && self.repairs.is_empty()
+    }
+
+    // takes the queued chunks with the color of the oldest one, nothing while tasks are running
+    fn next_class(&mut self) -> Vec<ChunkId> {
+        let running = !self.tasks.is_empty() || !self.repairs.is_empty();
+        let Some(first) = self.queued.first().filter(|_| !running) else {
+            return vec![];
+        };
+        let color = chunk_color(first);
+        let (class, rest) = std::mem::take(&mut self.queued)
+            .into_iter()
+            .partition(|id| chunk_color(id) == color);
+        self.queued = rest;
+        class
+    }
+
+    // starts generating the next class on the task pool, once the running one is finished
+    pub fn start(&mut self, world_map: &WorldMap, ctx: ChunkContext) {
+        let pool = AsyncComputeTaskPool::get_or_init(TaskPool::default);
+        for id in self.next_class() {
+            // a repair may have generated it in the meantime
+            if world_map.contains(&id) {
+                continue;
+            }
+            let builder = chunk_builder(world_map, &id, self.attempt_context(&id, ctx));
+            let tiles = ctx.tiles.clone();
+            let task = pool.spawn(async move { builder?.build_with_stats(&tiles) });
+            self.tasks.insert(id, task);
+        }
+    }
+
+    // the context of the next attempt of the chunk, the first one uses the seed of the world
+    fn attempt_context<'a>(&self, id: &ChunkId, ctx: ChunkContext<'a>) -> ChunkContext<'a> {
+        match self.attempts.get(id) {
+            Some(&attempt) => ctx.with_seed(WorldSeed(util::mix_seed(ctx.seed.0, attempt as u64))),
+            None => ctx,
+        }
+    }
+
+    // queues the chunk for another attempt, or gives it up with the error after the last one
+    fn retry(&mut self, id: ChunkId, error: WfcError, update: &mut ChunkUpdate) {
+        let attempts = self.attempts.entry(id.clone()).or_insert(0);
+        *attempts += 1;
+        if *attempts < CHUNK_ATTEMPTS {
+            self.queue(id);
+        } else {
+            update.failed.push((id, error));
+        }
+    }
+
+    // Adds the results of the running class to the world map once all of its tasks are finished,
+    // sorted by chunk so the same seed produces the same world however long the tasks take.
+    // Chunks that can not be solved next to their neighbors are repaired on another task, those
+    // repairs have to finish before the class does. Chunks whose neighbors changed while they were
+    // generated and chunks whose repair failed are retried with another seed. With wait it blocks
+    // until the running tasks are finished.
+    pub fn finish(
+        &mut self,
+        world_map: &mut WorldMap,
+        ctx: ChunkContext,
+        wait: bool,
+    ) -> ChunkUpdate {
+        for (id, result) in poll_tasks(&mut self.tasks, wait) {
+            self.built.push((id, result));
+        }
+        for (id, result) in poll_tasks(&mut self.repairs, wait) {
+            self.repaired.push((id, result));
+        }
+        let mut update = ChunkUpdate::default();
+        if !self.tasks.is_empty() || !self.repairs.is_empty() {
+            return update;
+        }
+
+        let mut built = std::mem::take(&mut self.built);
+        built.sort_by_key(|(id, _)| (id.x(), id.z()));
+        let mut unsolved = vec![];
+        for (id, result) in built {
+            let result = result.map(|(chunk, stats)| {
+                update.stats.push((id.clone(), stats));
+                chunk
+            });
+            match result {
+                Ok(chunk) if fits_neighbors(&chunk, world_map, ctx.rule_set) => {
+                    world_map.add_chunk(chunk);
+                    update.changed.push(id);
+                }
+                // a repair of a chunk with the same color changed the neighbors
+                Ok(_) => self.retry(id, WfcError::NeighborsChanged, &mut update),
+                Err(WfcError::Contradiction { .. } | WfcError::BacktrackLimit { .. }) => {
+                    unsolved.push(id)
+                }
+                Err(e) => update.failed.push((id, e)),
+            }
+        }
+        // the repairs start from the world map with every chunk of the class in it
+        let pool = AsyncComputeTaskPool::get_or_init(TaskPool::default);
+        for id in unsolved {
+            let task = pool.spawn(repair_task(world_map, &id, self.attempt_context(&id, ctx)));
+            self.repairs.insert(id, task);
+        }
+
+        let mut repaired = std::mem::take(&mut self.repaired);
+        repaired.sort_by_key(|(id, _)| (id.x(), id.z()));
+        for (id, result) in repaired {
+            match result.map(|repair| repair.apply(world_map)) {
+                Ok(Some(ids)) => {
+                    for id in ids {
+                        if !update.changed.contains(&id) {
+                            update.changed.push(id);
+                        }
+                    }
+                }
+                // an earlier repair of the class changed the chunks around it
+                Ok(None) => self.retry(id, WfcError::NeighborsChanged, &mut update),
+                Err(
+                    e @ (WfcError::Contradiction { .. }
+                    | WfcError::BacktrackLimit { .. }
+                    | WfcError::TileCount(_)
+                    | WfcError::Unreachable(_)),
+                ) => self.retry(id, e, &mut update),
+                Err(e) => update.failed.push((id, e)),
+            }
+        }
+        for id in update.changed.iter() {
+            self.attempts.remove(id);
+        }
+
+        for (id, _) in update.failed.iter() {
+            self.attempts.remove(id);
+            world_map.add_chunk(Chunk::new(id.clone(), world_map.dims(), None));
+        }
+        update
+    }
+}
+
+// takes the results of the tasks that are finished, with wait it blocks until all of them are
+fn poll_tasks<T>(tasks: &mut HashMap<ChunkId, Task<T>>, wait: bool) -> Vec<(ChunkId, T)> {
+    let mut finished = vec![];
+    for (id, task) in tasks.iter_mut() {
+        if let Some(result) = poll_task(task, wait) {
+            finished.push((id.clone(), result));
+        }
+    }
+    for (id, _) in finished.iter() {
+        tasks.remove(id);
+    }
+    finished
+}
+
+// the result of the task if it is finished, with wait it blocks until it is
+fn poll_task<T>(task: &mut Task<T>, wait: bool) -> Option<T> {
+    if wait {
+        Some(future::block_on(task))
+    } else {
+        future::block_on(future::poll_once(task))
+    }
+}
+
+fn chunk_context<'a>(
+    tiles: &'a Tiles,
+    rule_set: &'a AdjRuleSet,
+    seed: &WorldSeed,
+    height_field: &'a Option<Res<HeightField>>,
+    weight_field: &'a Option<Res<WorldWeightField>>,
+    constraints: &'a WorldConstraints,
+) -> ChunkContext<'a> {
+    ChunkContext::new(tiles, rule_set)
+        .with_seed(*seed)
+        .with_height_field(height_field.as_deref())
+        .with_weight_field(weight_field.as_ref().map(|field| &field.0))
+        .with_constraints(constraints)
+}
+
+// start generating the missing chunks around the focus point
+#[allow(clippy::too_many_arguments)]
+fn queue_chunks(
+    world_map: Res<WorldMap>,
+    tiles: Res<Tiles>,
+    rule_set: Res<AdjRuleSet>,
+    focus: Res<WorldFocusPoint>,
+    seed: Res<WorldSeed>,
+    height_field: Option<Res<HeightField>>,
+    weight_field: Option<Res<WorldWeightField>>,
+    constraints: Res<WorldConstraints>,
+    mut scheduler: ResMut<ChunkScheduler>,
+) {
+    let center = ChunkId::from_position(focus.pos, &world_map.dims());
+    for z in -CHUNK_SPAWN_DISTANCE..=CHUNK_SPAWN_DISTANCE {
+        for x in -CHUNK_SPAWN_DISTANCE..=CHUNK_SPAWN_DISTANCE {
+            let id = center.clone().x_offset(x).z_offset(z);
+            if !world_map.contains(&id) {
+                scheduler.queue(id);
+            }
+        }
+    }
+    let ctx = chunk_context(&tiles, &rule_set, &seed, &height_field, &weight_field, &constraints);
+    scheduler.start(&world_map, ctx);
+}
+
+// add the chunks whose generation or repair finished to the world and spawn their tiles
+#[allow(clippy::too_many_arguments)]
 fn spawn_chunks(
     mut world_map: ResMut<WorldMap>,
+    mut scheduler: ResMut<ChunkScheduler>,
+    ass: Res<AssetServer>,
     assets_gltf: Res<Assets<Gltf>>,
     tiles: Res<Tiles>,
     rule_set: Res<AdjRuleSet>,
     mut cmds: Commands,
-    focus: Res<WorldFocusPoint>,
+    seed: Res<WorldSeed>,
+    height_field: Option<Res<HeightField>>,
+    weight_field: Option<Res<WorldWeightField>>,
+    constraints: Res<WorldConstraints>,
 ) {
-    let mut chunks_to_spawn: Vec<ChunkId> = Vec::new();
-    // for z in (-CHUNK_SPAWN_DISTANCE)..CHUNK_SPAWN_DISTANCE {
-    //     for x in (-CHUNK_SPAWN_DISTANCE)..CHUNK_SPAWN_DISTANCE {
-    //         let id = ChunkId::from_position(focus.pos).x_offset(x).z_offset(z);
-    //         if !world_map.contains(&id) {
-    //             chunks_to_spawn.push(id);
-    //         }
-    //     }
-    // }
-    let id = ChunkId::new(0, 0);
-    if !world_map.contains(&id) {
-        chunks_to_spawn.push(id);
-    }
-
-    for id in &chunks_to_spawn {
-        // let chunk = Chunk::new(id.clone(), Some(TileID(0)));
-        let chunk = ChunkBuilder::new(id.clone())
-            .add_rule_set(rule_set.clone())
-            .build(&tiles);
-        for z in 0..CHUNK_SIZE {
-            for x in 0..CHUNK_SIZE {
-                for y in 0..CHUNK_HIGHT {
-                    let Some(tile_id) = &chunk.get_tile(x, y, z) else {
-                        continue;
-                    };
-                    let Some(tile) = tiles.0.get(tile_id) else {
-                        continue;
-                    };
-                    let Some(handle) = &tile.asset_handle else {
-                        continue;
-                    };
-                    let gltf = assets_gltf.get(handle).expect("Asset should be loaded");
-                    let transform = Transform {
-                        translation: chunk.pos() + Vec3::new(x as f32, y as f32, z as f32),
-                        rotation: tile.y_rotation.to_quat(),
-                        ..default()
-                    };
-                    let _entity = cmds.spawn((SceneBundle {
+    let ctx = chunk_context(&tiles, &rule_set, &seed, &height_field, &weight_field, &constraints);
+    let update = scheduler.finish(&mut world_map, ctx, false);
+    for (id, e) in update.failed {
+        error!("failed to generate chunk {:?}: {}", id, e);
+    }
+    for id in update.changed {
+        if !scheduler.unspawned.contains(&id) {
+            scheduler.unspawned.push(id);
+        }
+    }
+    let (ready, waiting) = std::mem::take(&mut scheduler.unspawned)
+        .into_iter()
+        .partition(|id| {
+            let chunk = world_map.get(id).expect("changed chunks are in the world map");
+            models_loaded(chunk, &tiles, &ass)
+        });
+    scheduler.unspawned = waiting;
+    // a repair can modify chunks that are already spawned
+    for id in ready {
+        for entity in scheduler.spawned.remove(&id).unwrap_or_default() {
+            cmds.entity(entity).despawn_recursive();
+        }
+        let chunk = world_map.get(&id).expect("changed chunks are in the world map");
+        let entities = spawn_chunk_tiles(&mut cmds, chunk, &tiles, &assets_gltf);
+        scheduler.spawned.insert(id.clone(), entities);
+        info!("spawned chunk with {:?}", &id);
+    }
+}
+
+// the models of the tiles in the chunk have finished loading, models that failed to load count as
+// finished, their tiles are left out
+fn models_loaded(chunk: &Chunk, tiles: &Tiles, ass: &AssetServer) -> bool {
+    let dims = chunk.dims();
+    (0..dims.size()).all(|z| {
+        (0..dims.size()).all(|x| {
+            (0..dims.height()).all(|y| {
+                let handle = chunk
+                    .get_tile(x, y, z)
+                    .and_then(|id| tiles.0.get(&id))
+                    .and_then(|tile| tile.asset_handle.as_ref());
+                !handle.is_some_and(|handle| {
+                    matches!(
+                        ass.get_load_state(handle),
+                        Some(LoadState::NotLoaded | LoadState::Loading)
+                    )
+                })
+            })
+        })
+    })
+}
+
+// The tile entities belong to the chunk, so they can be despawned with it.
+#[derive(Component)]
+pub struct ChunkTile(pub ChunkId);
+
+fn spawn_chunk_tiles(
+    cmds: &mut Commands,
+    chunk: &Chunk,
+    tiles: &Tiles,
+    assets_gltf: &Assets<Gltf>,
+) -> Vec<Entity> {
+    let dims = chunk.dims();
+    let mut entities = vec![];
+    for z in 0..dims.size() {
+        for x in 0..dims.size() {
+            for y in 0..dims.height() {
+                let Some(tile_id) = &chunk.get_tile(x, y, z) else {
+                    continue;
+                };
+                let Some(tile) = tiles.0.get(tile_id) else {
+                    continue;
+                };
+                let Some(handle) = &tile.asset_handle else {
+                    continue;
+                };
+                let Some(gltf) = assets_gltf.get(handle) else {
+                    continue;
+                };
+                let transform = Transform {
+                    translation: chunk.pos()
+                        + Vec3::new(x as f32, y as f32, z as f32) * dims.tile_size(),
+                    rotation: tile.y_rotation.to_quat(),
+                    scale: Vec3::splat(dims.tile_size()),
+                };
+                let entity = cmds.spawn((
+                    SceneBundle {
                         scene: gltf.scenes[0].clone(),
                         transform,
                         ..default()
-                    },));
-                }
+                    },
+                    ChunkTile(chunk.id()),
+                ));
+                entities.push(entity.id());
             }
         }
-        info!("spawned chunk with {:?}", &id);
-        world_map.add_chunk(chunk);
+    }
+    entities
+}
+
+// Every chunk is generated from a seed derived from this one, so the same world seed always
+// produces the same world. Insert it before the plugin to override the default.
+#[derive(Resource, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WorldSeed(pub u64);
+
+impl WorldSeed {
+    pub fn chunk_seed(&self, id: &ChunkId) -> u64 {
+        let seed = util::mix_seed(self.0, id.x() as u32 as u64);
+        util::mix_seed(seed, id.z() as u32 as u64)
+    }
+}
+
+// The constraints of single chunks, they are applied whenever the chunk is generated or repaired.
+// Chunks without an entry get the constraints of every_chunk, without those they are only
+// constrained by the rules.
+#[derive(Resource, Default, Clone)]
+pub struct WorldConstraints {
+    pub chunks: HashMap<ChunkId, ChunkConstraints>,
+    pub every_chunk: Option<ChunkConstraints>,
+}
+
+impl WorldConstraints {
+    pub fn get(&self, id: &ChunkId) -> Option<&ChunkConstraints> {
+        self.chunks.get(id).or(self.every_chunk.as_ref())
     }
 }
 
@@ -141,17 +525,203 @@ pub struct WorldFocusPoint {
     pub pos: Vec3,
 }
 
-#[derive(Resource, Default)]
+#[derive(Resource, Default, Clone)]
 pub struct WorldMap {
+    dims: ChunkDims,
     chunks: HashMap<ChunkId, Chunk>,
 }
 
 impl WorldMap {
+    pub fn new(dims: ChunkDims) -> Self {
+        Self {
+            dims,
+            chunks: HashMap::new(),
+        }
+    }
+
+    pub fn dims(&self) -> ChunkDims {
+        self.dims
+    }
+
     pub fn contains(&self, id: &ChunkId) -> bool {
         self.chunks.contains_key(id)
     }
 
+    pub fn get(&self, id: &ChunkId) -> Option<&Chunk> {
+        self.chunks.get(id)
+    }
+
+    // tile at the given world tile coordinates, None outside of the loaded chunks
+    pub fn get_world_tile(&self, pos: IVec3) -> Option<TileID> {
+        if pos.y < 0 || pos.y >= self.dims.height() as i32 {
+            return None;
+        }
+        let (id, (x, y, z)) = self.split_world_pos(pos);
+        self.chunks.get(&id)?.get_tile(x, y, z)
+    }
+
+    // overwrites the tile at the given world tile coordinates, returns the chunk that was changed
+    pub fn set_world_tile(&mut self, pos: IVec3, tile: Option<TileID>) -> Option<ChunkId> {
+        if pos.y < 0 || pos.y >= self.dims.height() as i32 {
+            return None;
+        }
+        let (id, (x, y, z)) = self.split_world_pos(pos);
+        self.chunks.get_mut(&id)?.set_tile(x, y, z, tile);
+        Some(id)
+    }
+
+    fn split_world_pos(&self, pos: IVec3) -> (ChunkId, (usize, usize, usize)) {
+        let size = self.dims.size() as i32;
+        let id = ChunkId::new(pos.x.div_euclid(size), pos.z.div_euclid(size));
+        let local = (
+            pos.x.rem_euclid(size) as usize,
+            pos.y as usize,
+            pos.z.rem_euclid(size) as usize,
+        );
+        (id, local)
+    }
+
     pub fn add_chunk(&mut self, chunk: Chunk) {
+        assert_eq!(chunk.dims(), self.dims, "chunk {:?} has other dimensions", chunk.id());
         self.chunks.insert(chunk.id(), chunk);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::fixtures::{assert_world_is_legal, landscape};
+    use super::*;
+
+    // an app with the systems that generate and spawn the chunks
+    fn systems_app(tiles: &Tiles, rules: &AdjRuleSet) -> App {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, AssetPlugin::default()))
+            .init_asset::<Gltf>()
+            .insert_resource(WorldMap::new(ChunkDims::DEFAULT))
+            .insert_resource(WorldSeed(4))
+            .insert_resource(WorldFocusPoint { pos: Vec3::ZERO })
+            .insert_resource(tiles.clone())
+            .insert_resource(rules.clone())
+            .init_resource::<WorldConstraints>()
+            .init_resource::<ChunkScheduler>()
+            .add_systems(Update, (queue_chunks, spawn_chunks));
+        app
+    }
+
+    fn generate_with_systems(tiles: &Tiles, rules: &AdjRuleSet) -> App {
+        let mut app = systems_app(tiles, rules);
+        let finished = (0..10_000).any(|_| {
+            app.update();
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            app.world.resource::<ChunkScheduler>().is_idle()
+        });
+        assert!(finished, "the chunks were not generated within 10000 updates");
+        app
+    }
+
+    #[test]
+    fn test_systems_generate_the_chunks_around_the_focus() {
+        let (tiles, rules) = landscape();
+        let ids: Vec<ChunkId> = (-1..=1)
+            .flat_map(|z| (-1..=1).map(move |x| ChunkId::new(x, z)))
+            .collect();
+
+        let app = generate_with_systems(&tiles, &rules);
+        let world_map = app.world.resource::<WorldMap>();
+        assert!(ids.iter().all(|id| world_map.contains(id)));
+        assert_world_is_legal(world_map, &rules);
+        let spawned = &app.world.resource::<ChunkScheduler>().spawned;
+        assert!(ids.iter().all(|id| spawned.contains_key(id)));
+
+        // the results are applied in the same order however long the tasks take
+        let again = generate_with_systems(&tiles, &rules);
+        let again = again.world.resource::<WorldMap>();
+        for id in ids.iter() {
+            assert!(world_map.get(id) == again.get(id), "chunk {:?} differs", id);
+        }
+        // and the same as without the systems
+        let mut generated = WorldMap::new(ChunkDims::DEFAULT);
+        let ctx = ChunkContext::new(&tiles, &rules).with_seed(WorldSeed(4));
+        generate_chunks(&mut generated, &ids, ctx).unwrap();
+        for id in ids.iter() {
+            assert!(world_map.get(id) == generated.get(id), "chunk {:?} differs", id);
+        }
+    }
+
+    #[test]
+    fn test_chunks_wait_for_the_models_of_their_tiles() {
+        let (mut tiles, rules) = landscape();
+        let mut app = systems_app(&tiles, &rules);
+        let handle: Handle<Gltf> = app.world.resource::<AssetServer>().load("missing.glb");
+        for tile in tiles.0.values_mut() {
+            tile.asset_handle = Some(handle.clone());
+        }
+        app.insert_resource(tiles.clone());
+
+        let chunk = Chunk::new(ChunkId::default(), ChunkDims::DEFAULT, Some(TileID(0)));
+        let ass = app.world.resource::<AssetServer>();
+        assert!(!models_loaded(&chunk, &tiles, ass));
+        let spawned = (0..10_000).any(|_| {
+            app.update();
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            let ass = app.world.resource::<AssetServer>();
+            let scheduler = app.world.resource::<ChunkScheduler>();
+            if !models_loaded(&chunk, &tiles, ass) {
+                assert!(scheduler.spawned.is_empty());
+            }
+            scheduler.is_idle() && scheduler.unspawned.is_empty()
+        });
+        assert!(spawned, "the chunks were not spawned within 10000 updates");
+        // the tiles of the model that failed to load are left out
+        let scheduler = app.world.resource::<ChunkScheduler>();
+        assert!(!scheduler.spawned.is_empty());
+        assert!(scheduler.spawned.values().all(|entities| entities.is_empty()));
+    }
+
+    #[test]
+    fn test_scheduler_reports_the_stats_of_the_built_chunks() {
+        let (tiles, rules) = landscape();
+        let ctx = ChunkContext::new(&tiles, &rules);
+        let mut world_map = WorldMap::new(ChunkDims::DEFAULT);
+        let mut scheduler = ChunkScheduler::default();
+        let ids = [ChunkId::new(0, 0), ChunkId::new(2, 0)];
+        for id in ids.iter() {
+            scheduler.queue(id.clone());
+        }
+
+        scheduler.start(&world_map, ctx);
+        let update = scheduler.finish(&mut world_map, ctx, true);
+        let built: Vec<&ChunkId> = update.stats.iter().map(|(id, _)| id).collect();
+        assert_eq!(built, ids.iter().collect::<Vec<_>>());
+        assert!(update.stats.iter().all(|(_, stats)| stats.collapses > 0));
+    }
+
+    #[test]
+    fn test_scheduler_gives_up_after_the_last_attempt() {
+        let (tiles, rules) = landscape();
+        let id = ChunkId::default();
+        // the ground is restricted to the lower levels, so no seed solves the chunk
+        let pin = ChunkConstraints::default().pin(UVec3::new(0, 3, 0), TileID(0));
+        let constraints = WorldConstraints {
+            chunks: [(id.clone(), pin)].into_iter().collect(),
+            ..default()
+        };
+        let ctx = ChunkContext::new(&tiles, &rules).with_constraints(&constraints);
+        let mut world_map = WorldMap::new(ChunkDims::DEFAULT);
+        let mut scheduler = ChunkScheduler::default();
+        scheduler.queue(id.clone());
+
+        let (mut attempts, mut failed) = (0, vec![]);
+        while !scheduler.is_idle() {
+            scheduler.start(&world_map, ctx);
+            attempts += scheduler.tasks.len();
+            failed.extend(scheduler.finish(&mut world_map, ctx, true).failed);
+        }
+        assert_eq!(attempts, CHUNK_ATTEMPTS as usize);
+        assert!(matches!(
+            failed.as_slice(),
+            [(failed, WfcError::Contradiction { .. })] if *failed == id
+        ));
+        assert!(world_map.contains(&id));
+    }
+}
@@ -0,0 +1,337 @@
+use std::ops::Range;
+
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use strum::IntoEnumIterator;
+
+use super::chunk::{Chunk, ChunkBuilder, ChunkId, WfcError};
+use super::dir::{Dir, Rotation};
+use super::{AdjRuleSet, AdjacencyRules, ChunkDims, Tile, TileID, Tiles, MAX_TILES};
+
+// A hand authored grid of tiles the overlapping model learns from, indexed like a chunk.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExampleMap {
+    size: UVec3,
+    tiles: Vec<TileID>,
+    // the example wraps around along x and z, so patterns can cross its edges
+    periodic: bool,
+}
+
+impl ExampleMap {
+    pub fn new(size: UVec3, tiles: Vec<TileID>) -> Self {
+        assert_eq!(
+            tiles.len(),
+            (size.x * size.y * size.z) as usize,
+            "the example needs a tile for every cell"
+        );
+        Self {
+            size,
+            tiles,
+            periodic: false,
+        }
+    }
+
+    pub fn with_periodic(mut self, periodic: bool) -> Self {
+        self.periodic = periodic;
+        self
+    }
+
+    // None if a cell of the chunk is empty
+    pub fn from_chunk(chunk: &Chunk) -> Option<Self> {
+        let dims = chunk.dims();
+        let mut tiles = Vec::with_capacity(dims.volume());
+        for y in 0..dims.height() {
+            for x in 0..dims.size() {
+                for z in 0..dims.size() {
+                    tiles.push(chunk.get_tile(x, y, z)?);
+                }
+            }
+        }
+        let size = UVec3::new(dims.size() as u32, dims.height() as u32, dims.size() as u32);
+        Some(Self::new(size, tiles))
+    }
+
+    pub fn size(&self) -> UVec3 {
+        self.size
+    }
+
+    pub fn is_periodic(&self) -> bool {
+        self.periodic
+    }
+
+    pub fn get(&self, x: u32, y: u32, z: u32) -> TileID {
+        let index = y * self.size.x * self.size.z + x * self.size.z + z;
+        self.tiles[index as usize]
+    }
+}
+
+// A block of n*n*n tiles that occurs in the example.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    // indexed like a chunk with the size and height n
+    pub tiles: Vec<TileID>,
+    // number of times the pattern occurs in the example
+    pub count: usize,
+    // levels of the example the lowest layer of the pattern was found on
+    pub levels: Range<usize>,
+}
+
+// Overlapping model: instead of sockets, the rules come from the patterns of an example. Every
+// cell is solved for a pattern, patterns may be neighbors if they agree where they overlap, so
+// every n*n*n block of the result occurs in the example. Each cell shows the lowest corner of its
+// pattern, the levels above the last row of patterns are taken from the patterns below them.
+pub struct OverlappingModel {
+    n: usize,
+    // levels of the example patterns were found on, a chunk can not have more levels of patterns
+    levels: usize,
+    patterns: Vec<Pattern>,
+    // every pattern is a tile of its own, its id is the index of the pattern
+    tiles: Tiles,
+    rules: AdjRuleSet,
+}
+
+impl OverlappingModel {
+    pub fn learn(example: &ExampleMap, n: usize) -> Result<Self, WfcError> {
+        let size = example.size;
+        if n == 0 || n > size.min_element() as usize {
+            return Err(WfcError::PatternSize(n));
+        }
+        let (max_x, max_z) = if example.periodic {
+            (size.x, size.z)
+        } else {
+            (size.x - n as u32 + 1, size.z - n as u32 + 1)
+        };
+
+        let mut patterns: Vec<Pattern> = vec![];
+        let mut found: HashMap<Vec<TileID>, usize> = HashMap::new();
+        for y in 0..=size.y - n as u32 {
+            for x in 0..max_x {
+                for z in 0..max_z {
+                    let tiles = (0..n.pow(3))
+                        .map(|index| {
+                            let (dx, dy, dz) = pattern_cell(n, index);
+                            let x = (x + dx as u32) % size.x;
+                            let z = (z + dz as u32) % size.z;
+                            example.get(x, y + dy as u32, z)
+                        })
+                        .collect::<Vec<_>>();
+                    let level = y as usize;
+                    if let Some(&id) = found.get(&tiles) {
+                        let pattern = &mut patterns[id];
+                        pattern.count += 1;
+                        pattern.levels.start = pattern.levels.start.min(level);
+                        pattern.levels.end = pattern.levels.end.max(level + 1);
+                        continue;
+                    }
+                    found.insert(tiles.clone(), patterns.len());
+                    patterns.push(Pattern {
+                        tiles,
+                        count: 1,
+                        levels: level..level + 1,
+                    });
+                }
+            }
+        }
+        if patterns.len() > MAX_TILES {
+            return Err(WfcError::TooManyPatterns(patterns.len()));
+        }
+
+        let mut tiles = HashMap::new();
+        let mut rules = HashMap::new();
+        for (id, pattern) in patterns.iter().enumerate() {
+            let tile = Tile {
+                id: TileID(id as u32),
+                prototype: format!("pattern{}", id),
+                asset_handle: None,
+                weight: pattern.count,
+                y_rotation: Rotation::Zero,
+                y_level: Some(pattern.levels.clone()),
+                walkable: false,
+                ramp: None,
+            };
+            tiles.insert(tile.id, tile);
+            let mut rule = AdjacencyRules::default();
+            for dir in Dir::iter() {
+                for (other_id, other) in patterns.iter().enumerate() {
+                    if overlaps(n, pattern, other, dir) {
+                        rule.insert(dir, TileID(other_id as u32));
+                    }
+                }
+            }
+            rules.insert(TileID(id as u32), rule);
+        }
+
+        Ok(Self {
+            n,
+            levels: size.y as usize - n + 1,
+            patterns,
+            tiles: Tiles(tiles),
+            rules: AdjRuleSet(rules),
+        })
+    }
+
+    pub fn patterns(&self) -> &[Pattern] {
+        &self.patterns
+    }
+
+    // the tiles that stand for the patterns, to build the builder of the model with
+    pub fn tiles(&self) -> &Tiles {
+        &self.tiles
+    }
+
+    // A builder for the patterns of the chunk, the result has to be decoded. The chunk needs to be
+    // at least as large as the patterns and at most as high as the example, every pattern only
+    // fits on the levels it was found on.
+    pub fn builder(&self, id: ChunkId, dims: ChunkDims) -> Result<ChunkBuilder, WfcError> {
+        if dims.height() < self.n || dims.size() < self.n {
+            return Err(WfcError::PatternSize(self.n));
+        }
+        if dims.height() - self.n + 1 > self.levels {
+            return Err(WfcError::PatternSize(self.n));
+        }
+        let pattern_dims = ChunkDims::new(dims.size(), dims.height() - self.n + 1)
+            .with_tile_size(dims.tile_size());
+        Ok(ChunkBuilder::new(id, pattern_dims).add_rule_set(self.rules.clone()))
+    }
+
+    // turns a chunk of patterns built by the builder of the model into a chunk of tiles
+    pub fn decode(&self, patterns: &Chunk) -> Chunk {
+        let pattern_dims = patterns.dims();
+        let dims = ChunkDims::new(pattern_dims.size(), pattern_dims.height() + self.n - 1)
+            .with_tile_size(pattern_dims.tile_size());
+        let mut chunk = Chunk::new(patterns.id(), dims, None);
+        for x in 0..dims.size() {
+            for z in 0..dims.size() {
+                for y in 0..dims.height() {
+                    let level = y.min(pattern_dims.height() - 1);
+                    let Some(pattern) = patterns.get_tile(x, level, z) else {
+                        continue;
+                    };
+                    let tiles = &self.patterns[pattern.0 as usize].tiles;
+                    chunk.set_tile(x, y, z, Some(tiles[pattern_index(self.n, 0, y - level, 0)]));
+                }
+            }
+        }
+        chunk
+    }
+
+    pub fn generate(&self, id: ChunkId, dims: ChunkDims, seed: u64) -> Result<Chunk, WfcError> {
+        let patterns = self.builder(id, dims)?.with_seed(seed).build(&self.tiles)?;
+        Ok(self.decode(&patterns))
+    }
+}
+
+fn pattern_index(n: usize, x: usize, y: usize, z: usize) -> usize {
+    y * n * n + x * n + z
+}
+
+fn pattern_cell(n: usize, index: usize) -> (usize, usize, usize) {
+    (index / n % n, index / (n * n), index % n)
+}
+
+// if other can lie next to pattern in the direction, shifted by one cell
+fn overlaps(n: usize, pattern: &Pattern, other: &Pattern, dir: Dir) -> bool {
+    let offset = dir.to_vec3().as_ivec3();
+    (0..n.pow(3)).all(|index| {
+        let (x, y, z) = pattern_cell(n, index);
+        let shifted = IVec3::new(x as i32, y as i32, z as i32) - offset;
+        let inside =
+            shifted.cmpge(IVec3::ZERO).all() && shifted.cmplt(IVec3::splat(n as i32)).all();
+        if !inside {
+            return true;
+        }
+        let (sx, sy, sz) = (shifted.x as usize, shifted.y as usize, shifted.z as usize);
+        pattern.tiles[index] == other.tiles[pattern_index(n, sx, sy, sz)]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // ground on the lowest level, stripes along x above it
+    fn striped_example() -> ExampleMap {
+        let size = UVec3::new(8, 2, 8);
+        let mut tiles = vec![];
+        for y in 0..size.y {
+            for x in 0..size.x {
+                for _ in 0..size.z {
+                    tiles.push(if y == 0 { TileID(0) } else { TileID(1 + x % 2) });
+                }
+            }
+        }
+        ExampleMap::new(size, tiles).with_periodic(true)
+    }
+
+    #[test]
+    fn test_patterns_are_counted() {
+        let example = striped_example();
+        let model = OverlappingModel::learn(&example, 2).unwrap();
+        // the stripes start on even or odd columns
+        assert_eq!(model.patterns().len(), 2);
+        let total: usize = model.patterns().iter().map(|pattern| pattern.count).sum();
+        assert_eq!(total, 8 * 8);
+        assert!(model
+            .patterns()
+            .iter()
+            .all(|pattern| pattern.levels == (0..1)));
+
+        let rules = &model.rules.0[&TileID(0)];
+        assert_eq!(rules.from_dir(Dir::Right), &[TileID(1)]);
+        assert_eq!(rules.from_dir(Dir::Forward), &[TileID(0)]);
+        assert!(rules.from_dir(Dir::Up).is_empty());
+    }
+
+    #[test]
+    fn test_result_is_locally_similar() {
+        let example = striped_example();
+        let model = OverlappingModel::learn(&example, 2).unwrap();
+        let dims = ChunkDims::new(12, 2);
+        let chunk = model.generate(ChunkId::default(), dims, 5).unwrap();
+        assert_eq!(chunk.dims(), dims);
+
+        // every block of the result occurs in the example
+        for x in 0..dims.size() - 1 {
+            for z in 0..dims.size() - 1 {
+                let block: Vec<TileID> = (0..8)
+                    .map(|index| {
+                        let (dx, dy, dz) = pattern_cell(2, index);
+                        chunk.get_tile(x + dx, dy, z + dz).unwrap()
+                    })
+                    .collect();
+                assert!(model
+                    .patterns()
+                    .iter()
+                    .any(|pattern| pattern.tiles == block));
+            }
+        }
+        assert_eq!(chunk.get_tile(3, 0, 3), Some(TileID(0)));
+        assert_ne!(chunk.get_tile(3, 1, 3), chunk.get_tile(4, 1, 3));
+    }
+
+    #[test]
+    fn test_too_many_patterns() {
+        // every 1x1x1 block is a pattern of its own
+        let size = UVec3::new(17, 1, 17);
+        let tiles = (0..size.x * size.z).map(TileID).collect();
+        let example = ExampleMap::new(size, tiles);
+        let result = OverlappingModel::learn(&example, 1);
+        assert_eq!(result.err(), Some(WfcError::TooManyPatterns(289)));
+    }
+
+    #[test]
+    fn test_pattern_size() {
+        let example = striped_example();
+        for n in [0, 3] {
+            let result = OverlappingModel::learn(&example, n);
+            assert_eq!(result.err(), Some(WfcError::PatternSize(n)));
+        }
+        // the chunk is lower than the patterns
+        let model = OverlappingModel::learn(&example, 2).unwrap();
+        let result = model.generate(ChunkId::default(), ChunkDims::new(8, 1), 0);
+        assert_eq!(result.err(), Some(WfcError::PatternSize(2)));
+        // or higher than the example
+        let result = model.generate(ChunkId::default(), ChunkDims::new(8, 3), 0);
+        assert_eq!(result.err(), Some(WfcError::PatternSize(2)));
+    }
+}
@@ -4,11 +4,9 @@ use std::ops::Range;
 // side is down
 use bevy::gltf::Gltf;
 use bevy::prelude::*;
+use bevy::utils::HashSet;
 
-use super::{
-    dir::{Dir, Rotation},
-    CHUNK_HIGHT,
-};
+use super::dir::{Dir, Rotation};
 
 #[derive(Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 pub enum Socket {
@@ -17,7 +15,91 @@ pub enum Socket {
     Sym(u16),
     Asym(u16),
     AsymMir(u16),
-    Vert(u16),
+    // the top or bottom of a tile, connects only in the rotations it allows
+    Vert(u16, VertRotation),
+}
+
+impl Socket {
+    // if the socket connects when the upper tile is rotated by relative against the lower tile
+    pub fn allows(&self, relative: Rotation) -> bool {
+        match self {
+            Socket::Vert(_, rotation) => rotation.allows(relative),
+            _ => true,
+        }
+    }
+}
+
+// The rotations of the upper tile relative to the lower tile a vertical socket connects in.
+#[derive(Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum VertRotation {
+    // both tiles have the same rotation
+    Same,
+    // the socket looks the same in every rotation, e.g. a flat top
+    Invariant,
+    // the same rotation or a half turn apart
+    Half,
+    // the upper tile is rotated by exactly this much
+    Offset(Rotation),
+}
+
+impl VertRotation {
+    pub fn allows(&self, relative: Rotation) -> bool {
+        match self {
+            VertRotation::Same => relative == Rotation::Zero,
+            VertRotation::Invariant => true,
+            VertRotation::Half => matches!(relative, Rotation::Zero | Rotation::Half),
+            VertRotation::Offset(offset) => relative == *offset,
+        }
+    }
+}
+
+// Which sockets connect to each other, declared by the tileset. A socket can connect to several
+// sockets, also to sockets of another kind. Vert sockets also need a rotation they allow.
+#[derive(Resource, Debug, Clone, Default, PartialEq, Eq)]
+pub struct SocketCompatibility(HashSet<(Socket, Socket)>);
+
+impl SocketCompatibility {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // the sockets connect both ways
+    pub fn connect(mut self, socket: Socket, other: Socket) -> Self {
+        self.0.insert((socket, other));
+        self.0.insert((other, socket));
+        self
+    }
+
+    pub fn connect_all(self, socket: Socket, others: &[Socket]) -> Self {
+        others
+            .iter()
+            .fold(self, |compatibility, &other| compatibility.connect(socket, other))
+    }
+
+    pub fn connects(&self, socket: Socket, other: Socket) -> bool {
+        self.0.contains(&(socket, other))
+    }
+
+    // if the socket of a tile connects to the socket of its neighbor in the world direction, with
+    // the rotations of both tiles
+    pub fn connects_rotated(
+        &self,
+        dir: Dir,
+        socket: Socket,
+        rotation: Rotation,
+        other: Socket,
+        other_rotation: Rotation,
+    ) -> bool {
+        if !self.connects(socket, other) {
+            return false;
+        }
+        let relative = match dir {
+            Dir::Up => other_rotation.combine(rotation.inverse()),
+            Dir::Down => rotation.combine(other_rotation.inverse()),
+            _ => return true,
+        };
+        socket.allows(relative) && other.allows(relative)
+    }
 }
 pub struct Prototype {
     pub name: &'static str,
@@ -32,7 +114,13 @@ pub struct Prototype {
     pub weight: usize,
 
     pub y_rotations: Vec<Rotation>,
+    // levels the tile may be placed on, the range can reach past the height of the chunks
     pub y_level: Option<Range<usize>>,
+
+    // units can stand on top of the tile
+    pub walkable: bool,
+    // the tile leads one level up to the cell next to it in this direction, in tile space
+    pub ramp: Option<Dir>,
 }
 
 impl Prototype {
@@ -53,13 +141,23 @@ pub struct Prototypes(pub Vec<Prototype>);
 
 // FIXME: This can be done using a new asset type
 pub fn load_prototypes(mut cmds: Commands, ass: Res<AssetServer>) {
-    let ground = ass.load("models/terrain/ground.glb");
-    let cliff_low = ass.load("models/terrain/cliff_low.glb");
-    let cliff_low_corner = ass.load("models/terrain/cliff_low_corner.glb");
-    let cliff_low_corner2 = ass.load("models/terrain/cliff_low_corner2.glb");
-    let cliff_upper = ass.load("models/terrain/cliff_upper.glb");
-    let cliff_upper_corner = ass.load("models/terrain/cliff_upper_corner.glb");
-    let cliff_upper_corner2 = ass.load("models/terrain/cliff_upper_corner2.glb");
+    let (prototypes, compatibility) = terrain_prototypes(|path| ass.load(path));
+    cmds.insert_resource(Prototypes(prototypes));
+    cmds.insert_resource(compatibility);
+}
+
+// The prototypes of the terrain and which of their sockets connect, load turns the path of a model
+// into its handle.
+pub fn terrain_prototypes(
+    mut load: impl FnMut(&'static str) -> Handle<Gltf>,
+) -> (Vec<Prototype>, SocketCompatibility) {
+    let ground = load("models/terrain/ground.glb");
+    let cliff_low = load("models/terrain/cliff_low.glb");
+    let cliff_low_corner = load("models/terrain/cliff_low_corner.glb");
+    let cliff_low_corner2 = load("models/terrain/cliff_low_corner2.glb");
+    let cliff_upper = load("models/terrain/cliff_upper.glb");
+    let cliff_upper_corner = load("models/terrain/cliff_upper_corner.glb");
+    let cliff_upper_corner2 = load("models/terrain/cliff_upper_corner2.glb");
     let ground_prt = Prototype {
         name: "ground",
         asset_handle: Some(ground),
@@ -72,6 +170,8 @@ pub fn load_prototypes(mut cmds: Commands, ass: Res<AssetServer>) {
         weight: 25,
         y_rotations: vec![Rotation::Zero],
         y_level: None,
+        walkable: true,
+        ramp: None,
     };
 
     let cliff_low_prt = Prototype {
@@ -79,7 +179,7 @@ pub fn load_prototypes(mut cmds: Commands, ass: Res<AssetServer>) {
         asset_handle: Some(cliff_low),
         p_x: Socket::Ground,
         n_x: Socket::Sym(1),
-        p_y: Socket::Vert(2),
+        p_y: Socket::Vert(2, VertRotation::Same),
         n_y: Socket::Ground,
         p_z: Socket::Asym(3),
         n_z: Socket::AsymMir(3),
@@ -90,7 +190,9 @@ pub fn load_prototypes(mut cmds: Commands, ass: Res<AssetServer>) {
             Rotation::Quarter,
             Rotation::ThreeQuarter,
         ],
-        y_level: Some(0..(CHUNK_HIGHT)),
+        y_level: Some(0..usize::MAX),
+        walkable: false,
+        ramp: None,
     };
 
     let cliff_low_corner_prt = Prototype {
@@ -98,7 +200,7 @@ pub fn load_prototypes(mut cmds: Commands, ass: Res<AssetServer>) {
         asset_handle: Some(cliff_low_corner),
         p_x: Socket::Asym(3),
         n_x: Socket::Sym(1),
-        p_y: Socket::Vert(3),
+        p_y: Socket::Vert(3, VertRotation::Same),
         n_y: Socket::Ground,
         p_z: Socket::Sym(1),
         n_z: Socket::AsymMir(3),
@@ -109,7 +211,9 @@ pub fn load_prototypes(mut cmds: Commands, ass: Res<AssetServer>) {
             Rotation::Quarter,
             Rotation::ThreeQuarter,
         ],
-        y_level: Some(0..(CHUNK_HIGHT)),
+        y_level: Some(0..usize::MAX),
+        walkable: false,
+        ramp: None,
     };
 
     let cliff_low_corner2_prt = Prototype {
@@ -117,7 +221,7 @@ pub fn load_prototypes(mut cmds: Commands, ass: Res<AssetServer>) {
         asset_handle: Some(cliff_low_corner2),
         p_x: Socket::Ground,
         n_x: Socket::AsymMir(3),
-        p_y: Socket::Vert(4),
+        p_y: Socket::Vert(4, VertRotation::Same),
         n_y: Socket::Ground,
         p_z: Socket::Asym(3),
         n_z: Socket::Ground,
@@ -128,7 +232,9 @@ pub fn load_prototypes(mut cmds: Commands, ass: Res<AssetServer>) {
             Rotation::Quarter,
             Rotation::ThreeQuarter,
         ],
-        y_level: Some(0..(CHUNK_HIGHT)),
+        y_level: Some(0..usize::MAX),
+        walkable: false,
+        ramp: None,
     };
 
     let cliff_upper_prt = Prototype {
@@ -137,7 +243,7 @@ pub fn load_prototypes(mut cmds: Commands, ass: Res<AssetServer>) {
         p_x: Socket::Sym(1),
         n_x: Socket::Air,
         p_y: Socket::Air,
-        n_y: Socket::Vert(2),
+        n_y: Socket::Vert(2, VertRotation::Same),
         p_z: Socket::Asym(4),
         n_z: Socket::AsymMir(4),
         weight: 1,
@@ -147,7 +253,9 @@ pub fn load_prototypes(mut cmds: Commands, ass: Res<AssetServer>) {
             Rotation::Quarter,
             Rotation::ThreeQuarter,
         ],
-        y_level: Some(1..(CHUNK_HIGHT + 1)),
+        y_level: Some(1..usize::MAX),
+        walkable: true,
+        ramp: None,
     };
 
     let cliff_upper_corner_prt = Prototype {
@@ -156,7 +264,7 @@ pub fn load_prototypes(mut cmds: Commands, ass: Res<AssetServer>) {
         p_x: Socket::Asym(4),
         n_x: Socket::Air,
         p_y: Socket::Air,
-        n_y: Socket::Vert(3),
+        n_y: Socket::Vert(3, VertRotation::Same),
         p_z: Socket::Air,
         n_z: Socket::AsymMir(4),
         weight: 1,
@@ -166,7 +274,9 @@ pub fn load_prototypes(mut cmds: Commands, ass: Res<AssetServer>) {
             Rotation::Quarter,
             Rotation::ThreeQuarter,
         ],
-        y_level: Some(1..(CHUNK_HIGHT + 1)),
+        y_level: Some(1..usize::MAX),
+        walkable: true,
+        ramp: None,
     };
 
     let cliff_upper_corner2_prt = Prototype {
@@ -175,7 +285,7 @@ pub fn load_prototypes(mut cmds: Commands, ass: Res<AssetServer>) {
         p_x: Socket::Sym(1),
         n_x: Socket::AsymMir(4),
         p_y: Socket::Air,
-        n_y: Socket::Vert(4),
+        n_y: Socket::Vert(4, VertRotation::Same),
         p_z: Socket::Asym(4),
         n_z: Socket::Sym(1),
         weight: 1,
@@ -185,7 +295,33 @@ pub fn load_prototypes(mut cmds: Commands, ass: Res<AssetServer>) {
             Rotation::Quarter,
             Rotation::ThreeQuarter,
         ],
-        y_level: Some(1..(CHUNK_HIGHT + 1)),
+        y_level: Some(1..usize::MAX),
+        walkable: true,
+        ramp: None,
+    };
+
+    // a slope in a straight cliff, it leads up to the ground behind the cliff and carries the
+    // upper part of the cliff like cliff_low does
+    // FIXME: there is no ramp model yet, so the ramp is not drawn
+    let ramp_prt = Prototype {
+        name: "ramp",
+        asset_handle: None,
+        p_x: Socket::Ground,
+        n_x: Socket::Sym(1),
+        p_y: Socket::Vert(2, VertRotation::Same),
+        n_y: Socket::Ground,
+        p_z: Socket::Asym(3),
+        n_z: Socket::AsymMir(3),
+        weight: 1,
+        y_rotations: vec![
+            Rotation::Zero,
+            Rotation::Half,
+            Rotation::Quarter,
+            Rotation::ThreeQuarter,
+        ],
+        y_level: Some(0..usize::MAX),
+        walkable: true,
+        ramp: Some(Dir::Right),
     };
 
     let air_prt = Prototype {
@@ -199,7 +335,9 @@ pub fn load_prototypes(mut cmds: Commands, ass: Res<AssetServer>) {
         n_z: Socket::Air,
         weight: 4,
         y_rotations: vec![Rotation::Zero],
-        y_level: Some(1..(CHUNK_HIGHT + 1)),
+        y_level: Some(1..usize::MAX),
+        walkable: false,
+        ramp: None,
     };
 
     let dirt_prt = Prototype {
@@ -213,7 +351,9 @@ pub fn load_prototypes(mut cmds: Commands, ass: Res<AssetServer>) {
         n_z: Socket::Ground,
         weight: 16,
         y_rotations: vec![Rotation::Zero],
-        y_level: Some(0..(CHUNK_HIGHT)),
+        y_level: Some(0..usize::MAX),
+        walkable: false,
+        ramp: None,
     };
 
     let assets = vec![
@@ -224,10 +364,20 @@ pub fn load_prototypes(mut cmds: Commands, ass: Res<AssetServer>) {
         cliff_upper_prt,
         cliff_upper_corner_prt,
         cliff_upper_corner2_prt,
+        ramp_prt,
         air_prt,
         dirt_prt,
     ];
-    cmds.insert_resource(Prototypes(assets));
+    let compatibility = SocketCompatibility::new()
+        .connect(Socket::Air, Socket::Air)
+        .connect(Socket::Ground, Socket::Ground)
+        .connect(Socket::Sym(1), Socket::Sym(1))
+        .connect(Socket::Asym(3), Socket::AsymMir(3))
+        .connect(Socket::Asym(4), Socket::AsymMir(4))
+        .connect(Socket::Vert(2, VertRotation::Same), Socket::Vert(2, VertRotation::Same))
+        .connect(Socket::Vert(3, VertRotation::Same), Socket::Vert(3, VertRotation::Same))
+        .connect(Socket::Vert(4, VertRotation::Same), Socket::Vert(4, VertRotation::Same));
+    (assets, compatibility)
 }
 
 #[derive(States, Default, Clone, Copy, Debug, Eq, PartialEq, Hash)]
@@ -254,3 +404,52 @@ pub fn check_prototypes_loaded(
     debug!("Prototypes loaded!");
     next_state.set(PrototypesLoadState::Finished);
 }
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+    use bevy::utils::HashMap;
+
+    use super::super::chunk::{ChunkBuilder, ChunkId};
+    use super::super::{generate_tiles_and_rules, AdjRuleSet, ChunkDims, Tiles};
+    use super::*;
+
+    // the tiles and rules of the terrain, without its models
+    fn terrain() -> (Tiles, AdjRuleSet) {
+        let (prototypes, compatibility) = terrain_prototypes(|_| Handle::default());
+        let mut world = World::new();
+        world.insert_resource(Prototypes(prototypes));
+        world.insert_resource(compatibility);
+        world.insert_resource(Tiles(HashMap::new()));
+        world.insert_resource(AdjRuleSet(HashMap::new()));
+        world.run_system_once(generate_tiles_and_rules);
+        let tiles = world.remove_resource::<Tiles>().unwrap();
+        (tiles, world.remove_resource::<AdjRuleSet>().unwrap())
+    }
+
+    #[test]
+    fn test_ramps_lead_up_the_cliffs() {
+        let (tiles, rules) = terrain();
+        let dims = ChunkDims::new(12, 3);
+        let chunk = ChunkBuilder::new(ChunkId::default(), dims)
+            .add_rule_set(rules)
+            .with_seed(1)
+            .with_reachability()
+            .build(&tiles)
+            .unwrap();
+        assert!(chunk.is_reachable(&tiles));
+
+        // the ground on top of the cliffs is only reachable over the ramps
+        let mut prototypes = vec![];
+        for x in 0..dims.size() {
+            for z in 0..dims.size() {
+                for y in 0..dims.height() {
+                    let tile = &tiles.0[&chunk.get_tile(x, y, z).unwrap()];
+                    prototypes.push((y, tile.prototype.as_str()));
+                }
+            }
+        }
+        assert!(prototypes.contains(&(0, "ramp")));
+        assert!(prototypes.contains(&(1, "ground")));
+    }
+}
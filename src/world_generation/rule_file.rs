@@ -0,0 +1,398 @@
+use std::fmt::Display;
+use std::ops::Range;
+use std::path::Path;
+
+use bevy::gltf::Gltf;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use serde::{Deserialize, Serialize};
+use strum::IntoEnumIterator;
+
+use super::dir::{Dir, Rotation};
+use super::{AdjRuleSet, AdjacencyRules, Tile, TileID, Tiles, MAX_TILES};
+
+// Human readable formats the rule set can be stored in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleFormat {
+    Ron,
+    Json,
+}
+
+impl RuleFormat {
+    // picks the format from the file extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "ron" => Some(Self::Ron),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RuleFileError {
+    Io(std::io::Error),
+    Ron(ron::Error),
+    // a broken RON file, with the position of the mistake
+    RonSyntax(ron::error::SpannedError),
+    Json(serde_json::Error),
+    // the extension of the path is neither .ron nor .json
+    UnknownFormat(String),
+    // a tile id that does not fit into a tile mask, in the tiles or the rules
+    TileOutOfRange(TileID),
+}
+
+impl Display for RuleFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "Rule File Error: {}", err),
+            Self::Ron(err) => write!(f, "Rule File Error: {}", err),
+            Self::RonSyntax(err) => write!(f, "Rule File Error: {}", err),
+            Self::Json(err) => write!(f, "Rule File Error: {}", err),
+            Self::UnknownFormat(path) => {
+                write!(f, "Rule File Error: {} is neither .ron nor .json", path)
+            }
+            Self::TileOutOfRange(id) => write!(
+                f,
+                "Rule File Error: {:?} is out of range, ids have to be below {}",
+                id, MAX_TILES
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RuleFileError {}
+
+impl From<std::io::Error> for RuleFileError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<ron::Error> for RuleFileError {
+    fn from(err: ron::Error) -> Self {
+        Self::Ron(err)
+    }
+}
+
+impl From<ron::error::SpannedError> for RuleFileError {
+    fn from(err: ron::error::SpannedError) -> Self {
+        Self::RonSyntax(err)
+    }
+}
+
+impl From<serde_json::Error> for RuleFileError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Json(err)
+    }
+}
+
+// A tile as it is stored, with the path of its asset instead of the handle.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TileEntry {
+    pub id: TileID,
+    pub prototype: String,
+    pub asset_path: Option<String>,
+    pub weight: usize,
+    pub y_rotation: Rotation,
+    pub y_level: Option<Range<usize>>,
+    pub walkable: bool,
+    pub ramp: Option<Dir>,
+    // None if the tile is not part of the rule set
+    pub rules: Option<RuleEntry>,
+}
+
+// The neighbors of a tile, sorted so that changes to the rules make small diffs.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleEntry {
+    pub p_x: Vec<TileID>,
+    pub n_x: Vec<TileID>,
+    pub p_y: Vec<TileID>,
+    pub n_y: Vec<TileID>,
+    pub p_z: Vec<TileID>,
+    pub n_z: Vec<TileID>,
+}
+
+impl From<&AdjacencyRules> for RuleEntry {
+    fn from(rules: &AdjacencyRules) -> Self {
+        let sorted = |dir: Dir| {
+            let mut ids = rules.from_dir(dir).to_vec();
+            ids.sort();
+            ids
+        };
+        Self {
+            p_x: sorted(Dir::Right),
+            n_x: sorted(Dir::Left),
+            p_y: sorted(Dir::Up),
+            n_y: sorted(Dir::Down),
+            p_z: sorted(Dir::Backward),
+            n_z: sorted(Dir::Forward),
+        }
+    }
+}
+
+impl From<&RuleEntry> for AdjacencyRules {
+    fn from(entry: &RuleEntry) -> Self {
+        let mut rules = AdjacencyRules::default();
+        for dir in Dir::iter() {
+            let ids = match dir {
+                Dir::Forward => &entry.n_z,
+                Dir::Backward => &entry.p_z,
+                Dir::Left => &entry.n_x,
+                Dir::Right => &entry.p_x,
+                Dir::Up => &entry.p_y,
+                Dir::Down => &entry.n_y,
+            };
+            for &id in ids {
+                rules.insert(dir, id);
+            }
+        }
+        rules
+    }
+}
+
+// Tiles and AdjRuleSet in a form that can be written to a file, reviewed and loaded back instead
+// of generating the rules from the prototypes.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleFile {
+    // sorted by id
+    pub tiles: Vec<TileEntry>,
+}
+
+impl RuleFile {
+    // tiles without a loaded asset path are stored without an asset
+    pub fn new(tiles: &Tiles, rule_set: &AdjRuleSet) -> Self {
+        let mut entries: Vec<TileEntry> = tiles
+            .0
+            .values()
+            .map(|tile| TileEntry {
+                id: tile.id,
+                prototype: tile.prototype.clone(),
+                asset_path: tile
+                    .asset_handle
+                    .as_ref()
+                    .and_then(|handle| handle.path())
+                    .map(|path| path.to_string()),
+                weight: tile.weight,
+                y_rotation: tile.y_rotation,
+                y_level: tile.y_level.clone(),
+                walkable: tile.walkable,
+                ramp: tile.ramp,
+                rules: rule_set.0.get(&tile.id).map(RuleEntry::from),
+            })
+            .collect();
+        entries.sort_by_key(|entry| entry.id);
+        Self { tiles: entries }
+    }
+
+    // Turns the file back into tiles and rules, load gets the handle of an asset path. The ids
+    // are checked first, a hand edited file can hold ids a tile mask has no room for.
+    pub fn into_rules(
+        self,
+        mut load: impl FnMut(String) -> Handle<Gltf>,
+    ) -> Result<(Tiles, AdjRuleSet), RuleFileError> {
+        for entry in self.tiles.iter() {
+            let rules = entry.rules.iter().flat_map(|rules| {
+                [
+                    &rules.p_x, &rules.n_x, &rules.p_y, &rules.n_y, &rules.p_z, &rules.n_z,
+                ]
+            });
+            let mut ids = std::iter::once(&entry.id).chain(rules.flatten());
+            if let Some(&id) = ids.find(|id| id.0 as usize >= MAX_TILES) {
+                return Err(RuleFileError::TileOutOfRange(id));
+            }
+        }
+
+        let mut tiles = HashMap::new();
+        let mut rule_set = HashMap::new();
+        for entry in self.tiles {
+            if let Some(rules) = &entry.rules {
+                rule_set.insert(entry.id, AdjacencyRules::from(rules));
+            }
+            let tile = Tile {
+                id: entry.id,
+                prototype: entry.prototype,
+                asset_handle: entry.asset_path.map(&mut load),
+                weight: entry.weight,
+                y_rotation: entry.y_rotation,
+                y_level: entry.y_level,
+                walkable: entry.walkable,
+                ramp: entry.ramp,
+            };
+            tiles.insert(tile.id, tile);
+        }
+        Ok((Tiles(tiles), AdjRuleSet(rule_set)))
+    }
+
+    pub fn serialize(&self, format: RuleFormat) -> Result<String, RuleFileError> {
+        Ok(match format {
+            RuleFormat::Ron => ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::new())?,
+            RuleFormat::Json => serde_json::to_string_pretty(self)?,
+        })
+    }
+
+    pub fn deserialize(text: &str, format: RuleFormat) -> Result<Self, RuleFileError> {
+        Ok(match format {
+            RuleFormat::Ron => ron::from_str(text)?,
+            RuleFormat::Json => serde_json::from_str(text)?,
+        })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), RuleFileError> {
+        let path = path.as_ref();
+        let text = self.serialize(format_of(path)?)?;
+        std::fs::write(path, text)?;
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, RuleFileError> {
+        let path = path.as_ref();
+        let format = format_of(path)?;
+        Self::deserialize(&std::fs::read_to_string(path)?, format)
+    }
+}
+
+fn format_of(path: &Path) -> Result<RuleFormat, RuleFileError> {
+    RuleFormat::from_path(path)
+        .ok_or_else(|| RuleFileError::UnknownFormat(path.display().to_string()))
+}
+
+// replaces the tiles and rules with the ones of the file, instead of generating them
+pub fn import_rule_set(
+    path: String,
+) -> impl FnMut(Res<AssetServer>, ResMut<Tiles>, ResMut<AdjRuleSet>) {
+    move |ass, mut tiles, mut rule_set| {
+        let loaded = RuleFile::load(&path).and_then(|file| file.into_rules(|path| ass.load(path)));
+        match loaded {
+            Ok((loaded_tiles, loaded_rules)) => {
+                info!("Loaded {} tiles from {}", loaded_tiles.0.len(), path);
+                *tiles = loaded_tiles;
+                *rule_set = loaded_rules;
+            }
+            Err(err) => error!("{}", err),
+        }
+    }
+}
+
+// writes the current tiles and rules to the file
+pub fn export_rule_set(path: String) -> impl FnMut(Res<Tiles>, Res<AdjRuleSet>) {
+    move |tiles, rule_set| match RuleFile::new(&tiles, &rule_set).save(&path) {
+        Ok(()) => info!("Saved {} tiles to {}", tiles.0.len(), path),
+        Err(err) => error!("{}", err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fixtures::tile;
+    use super::*;
+
+    fn tileset() -> (Tiles, AdjRuleSet) {
+        let ground = Tile {
+            weight: 25,
+            walkable: true,
+            ..tile(0, "ground")
+        };
+        let ramp = Tile {
+            weight: 2,
+            y_rotation: Rotation::Quarter,
+            y_level: Some(0..usize::MAX),
+            ramp: Some(Dir::Left),
+            ..tile(1, "ramp")
+        };
+        let mut rule = AdjacencyRules::default();
+        // inserted out of order
+        rule.insert(Dir::Right, TileID(1));
+        rule.insert(Dir::Right, TileID(0));
+        rule.insert(Dir::Down, TileID(0));
+        let tiles = Tiles(HashMap::from([(ground.id, ground), (ramp.id, ramp)]));
+        (tiles, AdjRuleSet(HashMap::from([(TileID(0), rule)])))
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let (tiles, rule_set) = tileset();
+        let file = RuleFile::new(&tiles, &rule_set);
+        assert_eq!(file.tiles.len(), 2);
+        assert_eq!(file.tiles[0].id, TileID(0));
+        let rules = file.tiles[0].rules.as_ref().unwrap();
+        assert_eq!(rules.p_x, vec![TileID(0), TileID(1)]);
+        assert_eq!(file.tiles[1].rules, None);
+
+        for format in [RuleFormat::Ron, RuleFormat::Json] {
+            let text = file.serialize(format).unwrap();
+            let loaded = RuleFile::deserialize(&text, format).unwrap();
+            assert_eq!(loaded, file);
+
+            let (loaded_tiles, loaded_rules) = loaded.into_rules(|_| unreachable!()).unwrap();
+            assert_eq!(loaded_tiles.0, tiles.0);
+            assert_eq!(loaded_rules.0.len(), 1);
+            let rule = &loaded_rules.0[&TileID(0)];
+            assert_eq!(rule.from_dir(Dir::Right), &[TileID(0), TileID(1)]);
+            assert_eq!(rule.from_dir(Dir::Down), &[TileID(0)]);
+            assert!(rule.mask(Dir::Right).contains(TileID(1)));
+            assert!(rule.from_dir(Dir::Up).is_empty());
+        }
+    }
+
+    #[test]
+    fn test_asset_paths_are_loaded() {
+        let (mut tiles, rule_set) = tileset();
+        let mut file = RuleFile::new(&tiles, &rule_set);
+        file.tiles[0].asset_path = Some("models/terrain/ground.glb".to_string());
+        let handle = Handle::<Gltf>::weak_from_u128(7);
+
+        let mut paths = vec![];
+        let (loaded_tiles, _) = file
+            .into_rules(|path| {
+                paths.push(path);
+                handle.clone()
+            })
+            .unwrap();
+        assert_eq!(paths, vec!["models/terrain/ground.glb".to_string()]);
+        tiles.0.get_mut(&TileID(0)).unwrap().asset_handle = Some(handle);
+        assert_eq!(loaded_tiles.0, tiles.0);
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        let (tiles, rule_set) = tileset();
+        let mut file = RuleFile::new(&tiles, &rule_set);
+        file.tiles[1].id = TileID(300);
+        let result = file.clone().into_rules(|_| unreachable!());
+        assert!(matches!(
+            result,
+            Err(RuleFileError::TileOutOfRange(TileID(300)))
+        ));
+
+        // the id is only used as a neighbor
+        file.tiles[1].id = TileID(1);
+        file.tiles[0].rules.as_mut().unwrap().n_z.push(TileID(256));
+        let result = file.into_rules(|_| unreachable!());
+        assert!(matches!(
+            result,
+            Err(RuleFileError::TileOutOfRange(TileID(256)))
+        ));
+
+        // the error points at the broken line
+        let text = "(\n    tiles: [\n        (id: 0,, prototype: \"ground\"),\n    ],\n)";
+        let Err(RuleFileError::RonSyntax(err)) = RuleFile::deserialize(text, RuleFormat::Ron)
+        else {
+            panic!("the file should not parse");
+        };
+        assert_eq!(err.position.line, 3);
+        assert!(err.to_string().starts_with("3:"));
+    }
+
+    #[test]
+    fn test_format_from_path() {
+        let format = |path: &str| RuleFormat::from_path(Path::new(path));
+        assert_eq!(format("assets/rules.ron"), Some(RuleFormat::Ron));
+        assert_eq!(format("rules.json"), Some(RuleFormat::Json));
+        assert_eq!(format("rules.txt"), None);
+        assert!(matches!(
+            RuleFile::load("rules.txt"),
+            Err(RuleFileError::UnknownFormat(_))
+        ));
+    }
+}
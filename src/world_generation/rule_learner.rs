@@ -0,0 +1,230 @@
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use strum::IntoEnumIterator;
+
+use super::chunk::{check_tile_ids, WfcError};
+use super::dir::{Dir, Rotation};
+use super::overlapping::ExampleMap;
+use super::{AdjRuleSet, AdjacencyRules, TileID, Tiles};
+
+// Learns the adjacency rules from a hand built example instead of matching sockets. Every pair of
+// neighbors in the example is allowed, every other pair is not, and the tiles are weighted by how
+// often they occur in the example.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RuleLearner {
+    // also learn from the example rotated around y, so a pair only has to be placed once
+    pub rotations: bool,
+}
+
+impl RuleLearner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_rotations(mut self, rotations: bool) -> Self {
+        self.rotations = rotations;
+        self
+    }
+
+    // Returns the tiles with the learned weights and the rules of the tiles that occur in the
+    // example, tiles that do not occur are left out of the rule set.
+    pub fn learn(
+        &self,
+        example: &ExampleMap,
+        tiles: &Tiles,
+    ) -> Result<(Tiles, AdjRuleSet), WfcError> {
+        check_tile_ids(tiles.0.keys().copied())?;
+        let rotations: Vec<Rotation> = if self.rotations {
+            Rotation::iter().collect()
+        } else {
+            vec![Rotation::Zero]
+        };
+        let rotated = rotated_tiles(tiles);
+        // the tile that ends up in the cell when the example is rotated
+        let rotate = |id: TileID, rotation: Rotation| -> Result<Option<TileID>, WfcError> {
+            let tile = tiles.0.get(&id).ok_or(WfcError::UnknownTile(id))?;
+            let variants = &rotated[tile.prototype.as_str()];
+            // prototypes with a single tile look the same in every rotation
+            if variants.len() == 1 {
+                return Ok(Some(variants[0].1));
+            }
+            let rotation = tile.y_rotation.combine(rotation);
+            Ok(variants
+                .iter()
+                .find(|(r, _)| *r == rotation)
+                .map(|&(_, id)| id))
+        };
+
+        let size = example.size().as_ivec3();
+        let mut counts: HashMap<TileID, usize> = HashMap::new();
+        let mut rules: HashMap<TileID, AdjacencyRules> = HashMap::new();
+        for y in 0..size.y {
+            for x in 0..size.x {
+                for z in 0..size.z {
+                    let id = example.get(x as u32, y as u32, z as u32);
+                    for &rotation in rotations.iter() {
+                        if let Some(id) = rotate(id, rotation)? {
+                            *counts.entry(id).or_default() += 1;
+                        }
+                    }
+
+                    for dir in Dir::iter() {
+                        let mut other = IVec3::new(x, y, z) + dir.to_vec3().as_ivec3();
+                        if example.is_periodic() {
+                            other.x = other.x.rem_euclid(size.x);
+                            other.z = other.z.rem_euclid(size.z);
+                        }
+                        if other.cmplt(IVec3::ZERO).any() || other.cmpge(size).any() {
+                            continue;
+                        }
+                        let other = example.get(other.x as u32, other.y as u32, other.z as u32);
+                        for &rotation in rotations.iter() {
+                            let (Some(id), Some(other)) =
+                                (rotate(id, rotation)?, rotate(other, rotation)?)
+                            else {
+                                continue;
+                            };
+                            let rule = rules.entry(id).or_default();
+                            rule.insert(dir.rotate_y(rotation), other);
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut learned = tiles.clone();
+        for (id, tile) in learned.0.iter_mut() {
+            if let Some(&count) = counts.get(id) {
+                tile.weight = count;
+            }
+        }
+        Ok((learned, AdjRuleSet(rules)))
+    }
+}
+
+// the tiles of every prototype with their rotations
+fn rotated_tiles(tiles: &Tiles) -> HashMap<&str, Vec<(Rotation, TileID)>> {
+    let mut rotated: HashMap<&str, Vec<(Rotation, TileID)>> = HashMap::new();
+    for tile in tiles.0.values() {
+        let variants = rotated.entry(tile.prototype.as_str()).or_default();
+        variants.push((tile.y_rotation, tile.id));
+    }
+    rotated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::chunk::{ChunkBuilder, ChunkId};
+    use super::super::fixtures::tile;
+    use super::super::{ChunkDims, Tile};
+    use super::*;
+
+    fn tileset() -> Tiles {
+        let ground = Tile {
+            walkable: true,
+            ..tile(0, "ground")
+        };
+        let mut tiles = HashMap::new();
+        for (i, rotation) in Rotation::iter().enumerate() {
+            let id = TileID(i as u32 + 1);
+            let wall = Tile {
+                y_rotation: rotation,
+                ..tile(id.0, "wall")
+            };
+            tiles.insert(id, wall);
+        }
+        tiles.insert(ground.id, ground);
+        Tiles(tiles)
+    }
+
+    #[test]
+    fn test_learn_pairs() {
+        let tiles = tileset();
+        // ground with a wall to its right, on top of another ground
+        let example = ExampleMap::new(
+            UVec3::new(2, 2, 1),
+            vec![TileID(0), TileID(0), TileID(0), TileID(1)],
+        );
+        let (learned, rules) = RuleLearner::new().learn(&example, &tiles).unwrap();
+        assert_eq!(learned.0[&TileID(0)].weight, 3);
+        assert_eq!(learned.0[&TileID(1)].weight, 1);
+        assert_eq!(rules.0.len(), 2);
+        let ground = &rules.0[&TileID(0)];
+        assert_eq!(ground.from_dir(Dir::Right), &[TileID(0), TileID(1)]);
+        assert_eq!(ground.from_dir(Dir::Up), &[TileID(0), TileID(1)]);
+        assert_eq!(rules.0[&TileID(1)].from_dir(Dir::Down), &[TileID(0)]);
+        assert!(ground.from_dir(Dir::Forward).is_empty());
+
+        let (learned, rules) = RuleLearner::new()
+            .with_rotations(true)
+            .learn(&example, &tiles)
+            .unwrap();
+        // the ground does not change when it is rotated
+        assert_eq!(learned.0[&TileID(0)].weight, 12);
+        assert_eq!(rules.0.len(), 5);
+        for (i, rotation) in Rotation::iter().enumerate() {
+            let wall = TileID(i as u32 + 1);
+            assert_eq!(learned.0[&wall].weight, 1);
+            assert_eq!(rules.0[&wall].from_dir(Dir::Down), &[TileID(0)]);
+            let dir = Dir::Right.rotate_y(rotation);
+            assert_eq!(rules.0[&TileID(0)].from_dir(dir), &[TileID(0), wall]);
+        }
+    }
+
+    #[test]
+    fn test_unknown_tile() {
+        let example = ExampleMap::new(UVec3::new(2, 1, 1), vec![TileID(0), TileID(9)]);
+        let result = RuleLearner::new().learn(&example, &tileset());
+        assert_eq!(result.err(), Some(WfcError::UnknownTile(TileID(9))));
+    }
+
+    #[test]
+    fn test_too_many_tiles() {
+        let mut tiles = tileset();
+        tiles.0.insert(TileID(300), tile(300, "far"));
+        let example = ExampleMap::new(UVec3::new(1, 1, 1), vec![TileID(0)]);
+        let result = RuleLearner::new().learn(&example, &tiles);
+        assert_eq!(result.err(), Some(WfcError::TooManyTiles(301)));
+    }
+
+    #[test]
+    fn test_learned_rules_reproduce_the_example() {
+        let tiles = tileset();
+        // every tile fits next to every other tile, apart from walls facing each other on x
+        let mut rules = HashMap::new();
+        for &id in tiles.0.keys() {
+            let mut rule = AdjacencyRules::default();
+            for dir in Dir::iter() {
+                for &other in tiles.0.keys() {
+                    let walls = id != TileID(0) && other != TileID(0);
+                    if !walls || !matches!(dir, Dir::Left | Dir::Right) {
+                        rule.insert(dir, other);
+                    }
+                }
+            }
+            rules.insert(id, rule);
+        }
+        let dims = ChunkDims::new(8, 2);
+        let chunk = ChunkBuilder::new(ChunkId::default(), dims)
+            .with_seed(4)
+            .add_rule_set(AdjRuleSet(rules.clone()))
+            .build(&tiles)
+            .unwrap();
+        let example = ExampleMap::from_chunk(&chunk).unwrap();
+
+        let (learned, learned_rules) = RuleLearner::new().learn(&example, &tiles).unwrap();
+        let total: usize = learned_rules.0.keys().map(|id| learned.0[id].weight).sum();
+        assert_eq!(total, dims.volume());
+        // only pairs that the original rules allow are learned
+        for (id, rule) in learned_rules.0.iter() {
+            for dir in Dir::iter() {
+                assert!((rule.mask(dir) & !rules[id].mask(dir)).is_empty());
+            }
+        }
+        let rebuilt = ChunkBuilder::new(ChunkId::default(), dims)
+            .with_seed(5)
+            .add_rule_set(learned_rules)
+            .build(&learned);
+        assert!(rebuilt.is_ok());
+    }
+}
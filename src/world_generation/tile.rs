@@ -1,36 +1,46 @@
 use bevy::gltf::Gltf;
 use bevy::prelude::*;
 use bevy::utils::HashMap;
+use serde::{Deserialize, Serialize};
 use strum::IntoEnumIterator;
 use std::ops::Range;
 
-use crate::world_generation::Socket;
-
-use super::{dir::Dir, dir::Rotation, Prototype, Prototypes};
+use super::{dir::Dir, dir::Rotation, Prototype, Prototypes, SocketCompatibility};
+use super::{TileMask, WfcError, MAX_TILES};
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Tile {
     pub id: TileID,
+    // name of the prototype the tile is a rotation of
+    pub prototype: String,
     pub asset_handle: Option<Handle<Gltf>>,
     pub weight: usize,
     pub y_rotation: Rotation,
     pub y_level: Option<Range<usize>>,
+    pub walkable: bool,
+    // in world space, the rotation is already applied
+    pub ramp: Option<Dir>,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct TileID(pub u32);
 
-#[derive(Resource)]
+#[derive(Resource, Clone)]
 pub struct Tiles(pub HashMap<TileID, Tile>);
 
-#[derive(Clone)]
+// The neighbors a tile allows in every direction. The lists are private, so they can only change
+// through insert, which keeps the masks the solver reads in sync with them.
+#[derive(Clone, Default)]
 pub struct AdjacencyRules {
-    pub p_x: Vec<TileID>,
-    pub n_x: Vec<TileID>,
-    pub p_y: Vec<TileID>,
-    pub n_y: Vec<TileID>,
-    pub p_z: Vec<TileID>,
-    pub n_z: Vec<TileID>,
+    p_x: Vec<TileID>,
+    n_x: Vec<TileID>,
+    p_y: Vec<TileID>,
+    n_y: Vec<TileID>,
+    p_z: Vec<TileID>,
+    n_z: Vec<TileID>,
+    // the same rules as bitsets, indexed by Dir
+    masks: [TileMask; 6],
 }
 
 impl AdjacencyRules {
@@ -45,14 +55,22 @@ impl AdjacencyRules {
         }
     }
 
-    pub fn from_dir_mut(&mut self, dir: Dir) -> &mut Vec<TileID> {
-        match dir {
+    pub fn mask(&self, dir: Dir) -> TileMask {
+        self.masks[dir as usize]
+    }
+
+    pub fn insert(&mut self, dir: Dir, id: TileID) {
+        let list = match dir {
             Dir::Forward => &mut self.n_z,
             Dir::Backward => &mut self.p_z,
             Dir::Left => &mut self.n_x,
             Dir::Right => &mut self.p_x,
             Dir::Up => &mut self.p_y,
             Dir::Down => &mut self.n_y,
+        };
+        if !self.masks[dir as usize].contains(id) {
+            list.push(id);
+            self.masks[dir as usize].insert(id);
         }
     }
 
@@ -64,6 +82,10 @@ impl AdjacencyRules {
             + self.p_z.len()
             + self.n_z.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 #[derive(Resource, Clone)]
@@ -71,29 +93,31 @@ pub struct AdjRuleSet(pub HashMap<TileID, AdjacencyRules>);
 
 pub fn generate_tiles_and_rules(
     prototypes: Res<Prototypes>,
+    compatibility: Res<SocketCompatibility>,
     mut tiles: ResMut<Tiles>,
     mut rule_set: ResMut<AdjRuleSet>,
 ) {
+    let tile_count: usize = prototypes.0.iter().map(|prt| prt.y_rotations.len()).sum();
+    if tile_count > MAX_TILES {
+        error!("{}", WfcError::TooManyTiles(tile_count));
+        return;
+    }
     let mut id = 0;
     for prototype in prototypes.0.iter() {
         for &rotation in &prototype.y_rotations {
             info!("New Tile {} : {} with rotation {:?}", id, prototype.name, rotation);
             let new_tile = Tile {
                 id: TileID(id),
+                prototype: prototype.name.to_string(),
                 asset_handle: prototype.asset_handle.clone(),
                 weight: prototype.weight,
                 y_rotation: rotation,
                 y_level: prototype.y_level.clone(),
+                walkable: prototype.walkable,
+                ramp: prototype.ramp.map(|dir| dir.rotate_y(rotation)),
             };
             tiles.0.insert(TileID(id), new_tile);
-            let mut rule = AdjacencyRules {
-                p_x: vec![],
-                n_x: vec![],
-                p_y: vec![],
-                n_y: vec![],
-                p_z: vec![],
-                n_z: vec![],
-            };
+            let mut rule = AdjacencyRules::default();
             let mut other_id = 0;
             for other_prt in prototypes.0.iter() {
                 for &other_rotation in &other_prt.y_rotations {
@@ -102,6 +126,7 @@ pub fn generate_tiles_and_rules(
                         rotation,
                         other_prt,
                         other_rotation,
+                        &compatibility,
                         &mut rule,
                         other_id,
                     );
@@ -119,42 +144,130 @@ fn append_rule(
     rotation: Rotation,
     other_prt: &Prototype,
     other_rotation: Rotation,
+    compatibility: &SocketCompatibility,
     rule: &mut AdjacencyRules,
     id: u32,
 ) {
     for dir in Dir::iter() {
-        let rot_dir = dir.rotate_y(rotation);
-        let other_rot_dir = dir.rotate_y(other_rotation).opposite();
+        // the sockets are defined in tile space, so map the world direction back into it
+        let rot_dir = dir.rotate_y(rotation.inverse());
+        let other_rot_dir = dir.rotate_y(other_rotation.inverse()).opposite();
         let sock = prototype.socket_from_dir(rot_dir);
         let other_sock = other_prt.socket_from_dir(other_rot_dir);
-        use Socket as S;
-        match (sock, other_sock) {
-            // (S::Nil, _) | (_, S::Nil) => (),
-            (S::Air, S::Air) | (S::Ground, S::Ground) => {
-                rule.from_dir_mut(dir).push(TileID(id));
-            },
-            (S::Sym(id0), S::Sym(id1)) if id0 == id1 => {
-                rule.from_dir_mut(dir).push(TileID(id));
-                // info!(
-                //     "new rule: {} with {:?} rotation connects to {} with {:?} rotation",
-                //     prototype.name, rotation, other_prt.name, other_rotation
-                // );
-            },
-            (S::Asym(id0), S::AsymMir(id1)) | (S::AsymMir(id0), S::Asym(id1)) if id0 == id1 => {
-                rule.from_dir_mut(dir).push(TileID(id));
-                // info!(
-                //     "new rule: {} with {:?} rotation connects to {} with {:?} rotation",
-                //     prototype.name, rotation, other_prt.name, other_rotation
-                // );
+        if compatibility.connects_rotated(dir, sock, rotation, other_sock, other_rotation) {
+            rule.insert(dir, TileID(id));
+            // info!(
+            //     "new rule: {} with {:?} rotation connects to {} with {:?} rotation",
+            //     prototype.name, rotation, other_prt.name, other_rotation
+            // );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+
+    use super::super::fixtures::prototype;
+    use super::super::{Socket, VertRotation};
+    use super::*;
+
+    #[test]
+    fn test_rules_follow_the_compatibility() {
+        let grass = prototype("grass", Socket::Sym(1), Socket::Air);
+        let sand = prototype("sand", Socket::Sym(2), Socket::Air);
+        let path = prototype("path", Socket::Sym(3), Socket::Air);
+        let rules = |compatibility: &SocketCompatibility| {
+            let mut rule = AdjacencyRules::default();
+            for (id, other) in [&grass, &sand, &path].into_iter().enumerate() {
+                let zero = Rotation::Zero;
+                append_rule(&grass, zero, other, zero, compatibility, &mut rule, id as u32);
             }
-            (S::Vert(id0), S::Vert(id1)) if id0 == id1 && rotation == other_rotation => {
-                rule.from_dir_mut(dir).push(TileID(id));
-                // info!(
-                //     "new rule: {} with {:?} rotation connects to {} with {:?} rotation",
-                //     prototype.name, rotation, other_prt.name, other_rotation
-                // );
+            rule
+        };
+
+        // equal sockets only connect when they are declared
+        let rule = rules(&SocketCompatibility::new());
+        assert!(rule.is_empty());
+
+        // grass blends into sand and into paths, which do not connect with each other
+        let compatibility = SocketCompatibility::new()
+            .connect_all(Socket::Sym(1), &[Socket::Sym(1), Socket::Sym(2), Socket::Sym(3)])
+            .connect(Socket::Ground, Socket::Air);
+        assert!(compatibility.connects(Socket::Sym(3), Socket::Sym(1)));
+        assert!(!compatibility.connects(Socket::Sym(3), Socket::Sym(2)));
+        let rule = rules(&compatibility);
+        assert_eq!(rule.from_dir(Dir::Right), &[TileID(0), TileID(1), TileID(2)]);
+        assert_eq!(rule.from_dir(Dir::Up), &[TileID(0), TileID(1), TileID(2)]);
+        // the pair connects both ways
+        assert_eq!(rule.from_dir(Dir::Down), rule.from_dir(Dir::Up));
+    }
+
+    #[test]
+    fn test_too_many_tiles_are_not_generated() {
+        let rotated = (0..MAX_TILES / 4 + 1).map(|_| Prototype {
+            y_rotations: Rotation::iter().collect(),
+            ..prototype("rotated", Socket::Sym(1), Socket::Air)
+        });
+        let mut world = World::new();
+        world.insert_resource(Prototypes(rotated.collect()));
+        world.insert_resource(SocketCompatibility::new());
+        world.insert_resource(Tiles(HashMap::new()));
+        world.insert_resource(AdjRuleSet(HashMap::new()));
+        world.run_system_once(generate_tiles_and_rules);
+        assert!(world.resource::<Tiles>().0.is_empty());
+        assert!(world.resource::<AdjRuleSet>().0.is_empty());
+    }
+
+    #[test]
+    fn test_vertical_sockets_follow_the_relative_rotation() {
+        // the rotations of the upper tile that may stand on the lower one in Zero rotation
+        let stacked = |vert: VertRotation| {
+            let socket = Socket::Vert(1, vert);
+            let mut lower = prototype("lower", Socket::Sym(1), socket);
+            lower.y_rotations = Rotation::iter().collect();
+            let mut upper = prototype("upper", Socket::Sym(1), Socket::Air);
+            upper.n_y = socket;
+            upper.y_rotations = Rotation::iter().collect();
+            let compatibility = SocketCompatibility::new().connect(socket, socket);
+
+            let mut rotations = vec![];
+            for other_rotation in Rotation::iter() {
+                let mut up = AdjacencyRules::default();
+                let zero = Rotation::Zero;
+                append_rule(&lower, zero, &upper, other_rotation, &compatibility, &mut up, 0);
+                let mut down = AdjacencyRules::default();
+                append_rule(&upper, other_rotation, &lower, zero, &compatibility, &mut down, 0);
+                // the rule holds both ways
+                assert_eq!(up.from_dir(Dir::Up), down.from_dir(Dir::Down), "{:?}", other_rotation);
+                if !up.from_dir(Dir::Up).is_empty() {
+                    rotations.push(other_rotation);
+                }
+            }
+            rotations
+        };
+
+        use Rotation as R;
+        assert_eq!(stacked(VertRotation::Same), vec![R::Zero]);
+        assert_eq!(stacked(VertRotation::Invariant), Rotation::iter().collect::<Vec<_>>());
+        assert_eq!(stacked(VertRotation::Half), vec![R::Zero, R::Half]);
+        assert_eq!(stacked(VertRotation::Offset(R::Quarter)), vec![R::Quarter]);
+    }
+
+    #[test]
+    fn test_insert_keeps_the_masks_in_sync() {
+        let mut rule = AdjacencyRules::default();
+        rule.insert(Dir::Right, TileID(3));
+        rule.insert(Dir::Right, TileID(1));
+        rule.insert(Dir::Right, TileID(3));
+        assert_eq!(rule.from_dir(Dir::Right), &[TileID(3), TileID(1)]);
+        assert_eq!(rule.len(), 2);
+        for dir in Dir::iter() {
+            let mut expected = TileMask::default();
+            for &id in rule.from_dir(dir) {
+                expected.insert(id);
             }
-            (_, _) => (),
+            assert_eq!(rule.mask(dir), expected);
         }
     }
 }
@@ -0,0 +1,175 @@
+use std::ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign, Not};
+
+use super::TileID;
+
+// Upper bound for the number of tiles, including all rotations, a rule set can contain.
+pub const MAX_TILES: usize = 256;
+const WORDS: usize = MAX_TILES / 64;
+
+// A set of tiles stored as a fixed width bitset indexed by the TileID.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TileMask([u64; WORDS]);
+
+impl TileMask {
+    pub const EMPTY: Self = Self([0; WORDS]);
+
+    pub fn single(id: TileID) -> Self {
+        let mut mask = Self::EMPTY;
+        mask.insert(id);
+        mask
+    }
+
+    pub fn insert(&mut self, id: TileID) {
+        let (word, bit) = Self::position(id);
+        self.0[word] |= 1 << bit;
+    }
+
+    pub fn remove(&mut self, id: TileID) {
+        let (word, bit) = Self::position(id);
+        self.0[word] &= !(1 << bit);
+    }
+
+    pub fn contains(&self, id: TileID) -> bool {
+        let (word, bit) = Self::position(id);
+        self.0[word] & (1 << bit) != 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.iter().map(|word| word.count_ones() as usize).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|&word| word == 0)
+    }
+
+    pub fn first(&self) -> Option<TileID> {
+        self.iter().next()
+    }
+
+    pub fn iter(&self) -> TileMaskIter {
+        TileMaskIter {
+            words: self.0,
+            word: 0,
+        }
+    }
+
+    fn position(id: TileID) -> (usize, usize) {
+        let index = id.0 as usize;
+        assert!(index < MAX_TILES, "{:?} exceeds MAX_TILES", id);
+        (index / 64, index % 64)
+    }
+}
+
+#[derive(Clone)]
+pub struct TileMaskIter {
+    words: [u64; WORDS],
+    word: usize,
+}
+
+impl Iterator for TileMaskIter {
+    type Item = TileID;
+
+    fn next(&mut self) -> Option<TileID> {
+        while self.word < WORDS {
+            let bits = &mut self.words[self.word];
+            if *bits != 0 {
+                let bit = bits.trailing_zeros();
+                *bits &= *bits - 1;
+                return Some(TileID((self.word * 64) as u32 + bit));
+            }
+            self.word += 1;
+        }
+        None
+    }
+}
+
+impl std::fmt::Debug for TileMask {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.iter().map(|id| id.0)).finish()
+    }
+}
+
+impl FromIterator<TileID> for TileMask {
+    fn from_iter<I: IntoIterator<Item = TileID>>(iter: I) -> Self {
+        let mut mask = Self::EMPTY;
+        for id in iter {
+            mask.insert(id);
+        }
+        mask
+    }
+}
+
+impl BitAnd for TileMask {
+    type Output = Self;
+
+    fn bitand(mut self, rhs: Self) -> Self {
+        self &= rhs;
+        self
+    }
+}
+
+impl BitAndAssign for TileMask {
+    fn bitand_assign(&mut self, rhs: Self) {
+        for (word, other) in self.0.iter_mut().zip(rhs.0) {
+            *word &= other;
+        }
+    }
+}
+
+impl BitOr for TileMask {
+    type Output = Self;
+
+    fn bitor(mut self, rhs: Self) -> Self {
+        self |= rhs;
+        self
+    }
+}
+
+impl BitOrAssign for TileMask {
+    fn bitor_assign(&mut self, rhs: Self) {
+        for (word, other) in self.0.iter_mut().zip(rhs.0) {
+            *word |= other;
+        }
+    }
+}
+
+impl Not for TileMask {
+    type Output = Self;
+
+    fn not(mut self) -> Self {
+        for word in self.0.iter_mut() {
+            *word = !*word;
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mask_operations() {
+        let a: TileMask = [TileID(0), TileID(63), TileID(64), TileID(200)]
+            .into_iter()
+            .collect();
+        let b: TileMask = [TileID(63), TileID(200), TileID(255)].into_iter().collect();
+
+        assert_eq!(a.len(), 4);
+        assert!(a.contains(TileID(64)) && !a.contains(TileID(65)));
+        assert_eq!(
+            (a & b).iter().collect::<Vec<_>>(),
+            vec![TileID(63), TileID(200)]
+        );
+        assert_eq!((a | b).len(), 5);
+        assert_eq!(
+            (a & !b).iter().collect::<Vec<_>>(),
+            vec![TileID(0), TileID(64)]
+        );
+
+        let mut c = a;
+        c.remove(TileID(0));
+        assert_eq!(c.first(), Some(TileID(63)));
+        assert!(TileMask::EMPTY.is_empty());
+    }
+}
@@ -1,18 +1,10 @@
-use super::*;
-
-
-#[inline]
-pub fn get_index(x: usize, y: usize, z: usize) -> usize {
-    y * CHUNK_AREA + x * CHUNK_SIZE + z
-}
-
+// SplitMix64 finalizer, used to derive well distributed seeds from the world seed
 #[inline]
-pub fn from_index(index: usize) -> (usize, usize, usize) {
-    let z = index % CHUNK_SIZE;
-    let x = index / CHUNK_SIZE % CHUNK_SIZE;
-    let y = index / CHUNK_AREA;
-
-    (x,y,z)
+pub fn mix_seed(seed: u64, value: u64) -> u64 {
+    let mut z = (seed ^ value).wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
 }
 
 #[cfg(test)]
@@ -20,17 +12,9 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_from_index() {
-        for z in 0..CHUNK_SIZE {
-            for x in 0..CHUNK_SIZE {
-                for y in 0..CHUNK_HIGHT {
-                    let index = get_index(x,y,z);
-                    let (x0,y0,z0) = from_index(index);
-                    assert_eq!(x, x0, "compared x={x} and x0={x0}, for index={index}");
-                    assert_eq!(y, y0, "compared y={y} and y0={y0}, for index={index}");
-                    assert_eq!(z, z0, "compared z={z} and z0={z0}, for index={index}");
-                }
-            }
-        }
+    fn test_mix_seed() {
+        assert_eq!(mix_seed(42, 7), mix_seed(42, 7));
+        assert_ne!(mix_seed(42, 7), mix_seed(42, 8));
+        assert_ne!(mix_seed(42, 7), mix_seed(43, 7));
     }
 }
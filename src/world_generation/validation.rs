@@ -0,0 +1,338 @@
+use std::fmt;
+use std::ops::Range;
+
+use bevy::prelude::*;
+use strum::IntoEnumIterator;
+
+use super::dir::{Dir, Rotation};
+use super::{AdjRuleSet, ChunkDims, Prototype, Prototypes, Socket, SocketCompatibility};
+use super::{TileID, Tiles, WorldMap};
+
+// A mistake in the tileset that leaves cells of the chunks empty or tiles unused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuleIssue {
+    // nothing may lie next to the tile in the direction, it only fits at the edge of a chunk
+    NoNeighbor {
+        tile: TileID,
+        prototype: String,
+        dir: Dir,
+    },
+    // the levels of the tile lie above the chunk, so it is never placed
+    UnreachableLevel {
+        tile: TileID,
+        prototype: String,
+        levels: Range<usize>,
+    },
+    // no face that can lie across from the socket connects to it, e.g. an Asym without an AsymMir
+    OneSidedSocket {
+        socket: Socket,
+    },
+    // the face only connects to faces of its own prototype
+    UnmatchedSocket {
+        prototype: &'static str,
+        dir: Dir,
+        socket: Socket,
+    },
+}
+
+impl fmt::Display for RuleIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoNeighbor {
+                tile,
+                prototype,
+                dir,
+            } => write!(
+                f,
+                "tile {} ({}) has no neighbor in direction {:?}",
+                tile.0, prototype, dir
+            ),
+            Self::UnreachableLevel {
+                tile,
+                prototype,
+                levels,
+            } => write!(
+                f,
+                "tile {} ({}) can never appear, its levels {:?} are out of range",
+                tile.0, prototype, levels
+            ),
+            Self::OneSidedSocket { socket } => {
+                write!(f, "socket {:?} is only used on one side", socket)
+            }
+            Self::UnmatchedSocket {
+                prototype,
+                dir,
+                socket,
+            } => write!(
+                f,
+                "socket {:?} of {} in direction {:?} matches no other prototype",
+                socket, prototype, dir
+            ),
+        }
+    }
+}
+
+// Everything the validation found, in a stable order so the binaries can print it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RuleReport {
+    pub issues: Vec<RuleIssue>,
+}
+
+impl RuleReport {
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    pub fn merge(mut self, other: RuleReport) -> Self {
+        self.issues.extend(other.issues);
+        self
+    }
+}
+
+impl fmt::Display for RuleReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_ok() {
+            return write!(f, "the rule set has no issues");
+        }
+        write!(f, "the rule set has {} issues:", self.issues.len())?;
+        for issue in self.issues.iter() {
+            write!(f, "\n  - {}", issue)?;
+        }
+        Ok(())
+    }
+}
+
+// Checks the generated tiles and rules against the chunks they are used in.
+pub fn validate_rules(tiles: &Tiles, rule_set: &AdjRuleSet, dims: &ChunkDims) -> RuleReport {
+    let mut ids: Vec<&TileID> = tiles.0.keys().collect();
+    ids.sort();
+
+    let mut report = RuleReport::default();
+    for id in ids {
+        let tile = &tiles.0[id];
+        if let Some(levels) = &tile.y_level {
+            if levels.is_empty() || levels.start >= dims.height() {
+                report.issues.push(RuleIssue::UnreachableLevel {
+                    tile: *id,
+                    prototype: tile.prototype.clone(),
+                    levels: levels.clone(),
+                });
+            }
+        }
+        for dir in Dir::iter() {
+            let allowed = rule_set
+                .0
+                .get(id)
+                .map_or(0, |rule| rule.from_dir(dir).len());
+            if allowed == 0 {
+                report.issues.push(RuleIssue::NoNeighbor {
+                    tile: *id,
+                    prototype: tile.prototype.clone(),
+                    dir,
+                });
+            }
+        }
+    }
+    report
+}
+
+// Checks the sockets of the prototypes, before they are turned into tiles and rules.
+pub fn validate_prototypes(
+    prototypes: &[Prototype],
+    compatibility: &SocketCompatibility,
+) -> RuleReport {
+    let mut one_sided: Vec<Socket> = vec![];
+    let mut connected: Vec<Socket> = vec![];
+    let mut unmatched = vec![];
+    for prototype in prototypes {
+        for dir in Dir::iter() {
+            let socket = prototype.socket_from_dir(dir);
+            let matches = matching_prototypes(prototype, dir, prototypes, compatibility);
+            if matches.is_empty() {
+                one_sided.push(socket);
+                continue;
+            }
+            connected.push(socket);
+            if matches.iter().all(|&name| name == prototype.name) {
+                unmatched.push(RuleIssue::UnmatchedSocket {
+                    prototype: prototype.name,
+                    dir,
+                    socket,
+                });
+            }
+        }
+    }
+    // a socket is one sided if none of the faces it is used on connects to anything
+    one_sided.retain(|socket| !connected.contains(socket));
+    one_sided.sort();
+    one_sided.dedup();
+
+    let mut report = RuleReport::default();
+    report.issues.extend(
+        one_sided
+            .into_iter()
+            .map(|socket| RuleIssue::OneSidedSocket { socket }),
+    );
+    report.issues.extend(unmatched);
+    report
+}
+
+// names of the prototypes with a face that connects to the face of the prototype in the direction,
+// in any of their rotations, the same way the rules are generated
+fn matching_prototypes(
+    prototype: &Prototype,
+    dir: Dir,
+    prototypes: &[Prototype],
+    compatibility: &SocketCompatibility,
+) -> Vec<&'static str> {
+    let socket = prototype.socket_from_dir(dir);
+    let connects = |rotation: Rotation, other: &Prototype, other_rotation: Rotation| {
+        let world_dir = dir.rotate_y(rotation);
+        let other_dir = world_dir.rotate_y(other_rotation.inverse()).opposite();
+        let other_socket = other.socket_from_dir(other_dir);
+        compatibility.connects_rotated(world_dir, socket, rotation, other_socket, other_rotation)
+    };
+
+    prototypes
+        .iter()
+        .filter(|other| {
+            prototype.y_rotations.iter().any(|&rotation| {
+                other
+                    .y_rotations
+                    .iter()
+                    .any(|&other_rotation| connects(rotation, other, other_rotation))
+            })
+        })
+        .map(|other| other.name)
+        .collect()
+}
+
+// logs the issues of the tileset once the tiles and rules are generated
+pub fn report_rule_issues(
+    prototypes: Res<Prototypes>,
+    compatibility: Res<SocketCompatibility>,
+    tiles: Res<Tiles>,
+    rule_set: Res<AdjRuleSet>,
+    world_map: Option<Res<WorldMap>>,
+) {
+    let dims = world_map.map_or(ChunkDims::DEFAULT, |world_map| world_map.dims());
+    let report = validate_prototypes(&prototypes.0, &compatibility)
+        .merge(validate_rules(&tiles, &rule_set, &dims));
+    log_report(&report);
+}
+
+// logs the issues of rules loaded from a file, the prototypes did not take part in them
+pub fn report_imported_rule_issues(
+    tiles: Res<Tiles>,
+    rule_set: Res<AdjRuleSet>,
+    world_map: Option<Res<WorldMap>>,
+) {
+    let dims = world_map.map_or(ChunkDims::DEFAULT, |world_map| world_map.dims());
+    log_report(&validate_rules(&tiles, &rule_set, &dims));
+}
+
+fn log_report(report: &RuleReport) {
+    if report.is_ok() {
+        info!("{}", report);
+    } else {
+        warn!("{}", report);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::utils::HashMap;
+
+    use super::super::fixtures::{prototype, tile};
+    use super::super::{AdjacencyRules, Tile};
+    use super::*;
+
+    #[test]
+    fn test_validate_rules() {
+        let ground = tile(0, "ground");
+        let roof = Tile {
+            y_level: Some(6..usize::MAX),
+            ..tile(1, "roof")
+        };
+        let tiles = Tiles(HashMap::from([(ground.id, ground), (roof.id, roof)]));
+        let mut rule = AdjacencyRules::default();
+        for dir in Dir::iter().filter(|&dir| dir != Dir::Up) {
+            rule.insert(dir, TileID(0));
+        }
+        let rule_set = AdjRuleSet(HashMap::from([(TileID(0), rule)]));
+
+        let report = validate_rules(&tiles, &rule_set, &ChunkDims::new(4, 8));
+        assert_eq!(
+            report.issues[0],
+            RuleIssue::NoNeighbor {
+                tile: TileID(0),
+                prototype: "ground".to_string(),
+                dir: Dir::Up,
+            }
+        );
+        // the roof has no rules at all
+        assert_eq!(report.issues.len(), 1 + 6);
+
+        // the roof is above lower chunks
+        let report = validate_rules(&tiles, &rule_set, &ChunkDims::new(4, 4));
+        assert_eq!(report.issues.len(), 1 + 1 + 6);
+        assert!(report.issues.contains(&RuleIssue::UnreachableLevel {
+            tile: TileID(1),
+            prototype: "roof".to_string(),
+            levels: 6..usize::MAX,
+        }));
+    }
+
+    #[test]
+    fn test_validate_prototypes() {
+        let grass = prototype("grass", Socket::Sym(1), Socket::Air);
+        let mut cliff = prototype("cliff", Socket::Sym(1), Socket::Air);
+        cliff.p_x = Socket::Asym(2);
+        let compatibility = SocketCompatibility::new()
+            .connect(Socket::Sym(1), Socket::Sym(1))
+            .connect(Socket::Air, Socket::Ground)
+            .connect(Socket::Asym(2), Socket::AsymMir(2));
+        let mut prototypes = vec![grass, cliff];
+        let report = validate_prototypes(&prototypes, &compatibility);
+        assert_eq!(
+            report.issues,
+            vec![
+                RuleIssue::OneSidedSocket {
+                    socket: Socket::Asym(2)
+                },
+                // the left side of the grass can only face the cliff, which does not connect
+                RuleIssue::UnmatchedSocket {
+                    prototype: "grass",
+                    dir: Dir::Left,
+                    socket: Socket::Sym(1)
+                },
+            ]
+        );
+
+        // a mirrored socket on the grass only connects to the cliff
+        prototypes[0].n_x = Socket::AsymMir(2);
+        let report = validate_prototypes(&prototypes, &compatibility);
+        assert!(report.is_ok(), "{}", report);
+
+        // the sand only connects to itself
+        let sand = prototype("sand", Socket::Sym(3), Socket::Air);
+        let compatibility = compatibility.connect(Socket::Sym(3), Socket::Sym(3));
+        prototypes.push(sand);
+        let report = validate_prototypes(&prototypes, &compatibility);
+        let unmatched: Vec<Dir> = report
+            .issues
+            .iter()
+            .map(|issue| match issue {
+                RuleIssue::UnmatchedSocket { prototype, dir, .. } => {
+                    assert_eq!(*prototype, "sand");
+                    *dir
+                }
+                _ => panic!("unexpected issue {}", issue),
+            })
+            .collect();
+        assert_eq!(
+            unmatched,
+            vec![Dir::Forward, Dir::Backward, Dir::Left, Dir::Right]
+        );
+    }
+}
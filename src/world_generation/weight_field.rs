@@ -0,0 +1,180 @@
+use std::sync::Arc;
+
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+use super::height_field::value_noise;
+use super::util::mix_seed;
+use super::{HeightField, Tile};
+
+// Weights that depend on the location, the solver asks for the weights of the tiles of every
+// cell. A weight of zero or less keeps the tile out of the cell.
+pub trait WeightField: Send + Sync {
+    // pos is in world tile coordinates
+    fn weight(&self, pos: IVec3, tile: &Tile) -> f32;
+
+    // the weights of the tiles in the cell, in the same order, fields that do work per cell
+    // override it to do it once for all tiles
+    fn weights(&self, pos: IVec3, tiles: &[&Tile]) -> Vec<f32> {
+        tiles.iter().map(|tile| self.weight(pos, tile)).collect()
+    }
+}
+
+// Makes a weight field available to the world generation.
+#[derive(Resource, Clone)]
+pub struct WorldWeightField(pub Arc<dyn WeightField>);
+
+// Walkable tiles become less likely the further they are from the surface level, within the
+// tolerance of the height field.
+impl WeightField for HeightField {
+    fn weight(&self, pos: IVec3, tile: &Tile) -> f32 {
+        let weight = tile.weight as f32;
+        if !tile.walkable {
+            return weight;
+        }
+        let distance = self.level(pos.x, pos.z).abs_diff(pos.y as usize);
+        weight * 0.5f32.powi(distance as i32)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Biome {
+    pub name: String,
+    // factor for the weight of the prototype, prototypes that are not listed keep their weight
+    pub factors: HashMap<String, f32>,
+}
+
+impl Biome {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            ..default()
+        }
+    }
+
+    pub fn with_factor(mut self, prototype: &str, factor: f32) -> Self {
+        self.factors.insert(prototype.to_string(), factor);
+        self
+    }
+
+    fn factor(&self, prototype: &str) -> f32 {
+        self.factors.get(prototype).copied().unwrap_or(1.0)
+    }
+}
+
+// Spreads the biomes over the world with noise, the weights are blended where biomes meet, so
+// the change is smooth and does not depend on chunk borders.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BiomeField {
+    pub seed: u64,
+    // size of a biome in tiles
+    pub scale: f32,
+    // the higher, the sharper the borders between biomes
+    pub sharpness: f32,
+    pub biomes: Vec<Biome>,
+}
+
+impl BiomeField {
+    pub fn new(seed: u64, biomes: Vec<Biome>) -> Self {
+        Self {
+            seed,
+            scale: 64.0,
+            sharpness: 4.0,
+            biomes,
+        }
+    }
+
+    pub fn with_scale(mut self, scale: f32) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    pub fn with_sharpness(mut self, sharpness: f32) -> Self {
+        self.sharpness = sharpness;
+        self
+    }
+
+    // share of every biome at the world tile coordinates, the shares add up to one
+    pub fn blend(&self, x: i32, z: i32) -> Vec<f32> {
+        let (x, z) = (x as f32 / self.scale, z as f32 / self.scale);
+        let mut shares: Vec<f32> = (0..self.biomes.len())
+            .map(|i| {
+                let noise = value_noise(mix_seed(self.seed, i as u64), x, z);
+                noise.powf(self.sharpness)
+            })
+            .collect();
+        let total: f32 = shares.iter().sum();
+        for share in shares.iter_mut() {
+            *share = if total > 0.0 {
+                *share / total
+            } else {
+                1.0 / self.biomes.len() as f32
+            };
+        }
+        shares
+    }
+
+    // the weight of the tile with the shares of the biomes at its position
+    fn blended_weight(&self, shares: &[f32], tile: &Tile) -> f32 {
+        let factor: f32 = shares
+            .iter()
+            .zip(self.biomes.iter())
+            .map(|(share, biome)| share * biome.factor(&tile.prototype))
+            .sum();
+        tile.weight as f32 * factor
+    }
+}
+
+impl WeightField for BiomeField {
+    fn weight(&self, pos: IVec3, tile: &Tile) -> f32 {
+        self.blended_weight(&self.blend(pos.x, pos.z), tile)
+    }
+
+    // the noise of the biomes is evaluated once for the cell
+    fn weights(&self, pos: IVec3, tiles: &[&Tile]) -> Vec<f32> {
+        let shares = self.blend(pos.x, pos.z);
+        tiles
+            .iter()
+            .map(|tile| self.blended_weight(&shares, tile))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fixtures::tile;
+    use super::*;
+
+    #[test]
+    fn test_biomes_blend_smoothly() {
+        let flatlands = Biome::new("flatlands").with_factor("cliff", 0.0);
+        let highlands = Biome::new("highlands").with_factor("cliff", 4.0);
+        let field = BiomeField::new(3, vec![flatlands, highlands]).with_scale(16.0);
+        let cliff = Tile {
+            weight: 2,
+            ..tile(0, "cliff")
+        };
+        let ground = Tile {
+            weight: 2,
+            ..tile(0, "ground")
+        };
+
+        let mut weights = vec![];
+        for x in -64..64 {
+            let blend = field.blend(x, 5);
+            assert!((blend.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+            let weight = field.weight(IVec3::new(x, 0, 5), &cliff);
+            assert!((0.0..=8.0).contains(&weight));
+            let cell = field.weights(IVec3::new(x, 0, 5), &[&ground, &cliff]);
+            assert_eq!(
+                cell,
+                vec![field.weight(IVec3::new(x, 0, 5), &ground), weight]
+            );
+            assert!((field.weight(IVec3::new(x, 0, 5), &ground) - 2.0).abs() < 1e-5);
+            weights.push(weight);
+        }
+        // both biomes show up, without jumps in between
+        assert!(weights.iter().any(|&w| w < 1.0) && weights.iter().any(|&w| w > 6.0));
+        assert!(weights.windows(2).all(|w| (w[0] - w[1]).abs() < 2.0));
+    }
+}